@@ -1,10 +1,86 @@
-use crate::state::PlayMode;
+use crate::effects::Effect;
+use crate::euclid::Euclid;
+use crate::state::{ClockRate, Condition, PlayMode, View};
+use serde::{Deserialize, Serialize};
 
+/**
+ * What a copy, paste or clear acts upon. Steps are on the current page of the active
+ * layer, and layers are in the active section.
+ */
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub enum Scope {
+    Step { x: u32 },
+    Layer { pos: u32 },
+    Section { pos: u32 },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub enum Action {
     Noop,
+    // Show this controller in the automation view of the active layer.
+    AutomationLaneChange { cc: u8 },
+    // Switch the active layer between playing chords and arpeggiating them.
+    ArpeggioToggle,
+    Clear(Scope),
+    // Keep a copy of something on the clipboard, to paste somewhere else.
+    Copy(Scope),
+    // Copy one thing straight onto another of the same kind.
+    Duplicate { from: Scope, to: Scope },
+    // Move the active layer on to the next direction it can play in.
+    DirectionCycle,
+    // Make the chord on the step under pad x the custom chord of the active
+    // layer.
+    ChordMemorize { x: u32 },
+    // Write the chord of the active layer onto the step under pad x, from the
+    // row under pad y.
+    ChordStamp { x: u32, y: u32 },
+    // Switch the active layer between drum voices and pitches.
+    DrumModeToggle,
+    // Hold an effect down over playback, or let go of it.
+    EffectPress(Effect),
+    EffectRelease(Effect),
+    // Fill a row of the active layer with a Euclidean rhythm.
+    EuclidFill { row: usize, euclid: Euclid },
+    FillToggle,
+    FollowToggle,
     GridToggle { x: u32, y: u32 },
+    // A pad pressed and let go of while the grid is a keyboard.
+    KeyPress { x: u32, y: u32 },
+    KeyRelease { x: u32, y: u32 },
+    // A key pressed on the keyboard.
+    KeyboardNote { pitch: u8 },
+    // Make a layer end on the step under pad x of the current page.
+    LayerLengthChange { pos: u32, x: u32 },
+    LayerRateChange { pos: u32, rate: ClockRate },
     LayerSelect { pos: u32 },
+    // Set the value of the controller in the automation view for the step
+    // under pad x.
+    LockChange { x: u32, value: u8 },
+    // Stop the active layer from mutating any further, or let it carry on.
+    MutationFreezeToggle,
+    PageSelect { pos: u32 },
+    // Put what is on the clipboard in place of something of the same kind.
+    Paste(Scope),
     PlayModeChange(PlayMode),
+    // Move on to the next way of recording from the keyboard.
+    RecordModeCycle,
+    Redo,
+    // Scroll the rows of the active layer. Positive is up.
+    RowScroll { rows: i32 },
     SectionSelect { pos: u32 },
+    // Play the step under pad x earlier or later, by a percentage of a step.
+    StepNudge { x: u32, amount: i32 },
+    // Give the step under pad x a condition of this kind, or the next one of
+    // the kind if it already has one.
+    StepConditionCycle { x: u32, kind: Condition },
+    // Strike the step under pad x this many times within the step.
+    StepRatchet { x: u32, ratchets: u32 },
+    // A tap of the tempo, this many milliseconds after starting up.
+    TapTempo { ms: u64 },
     TimeInterval,
+    // Switch keys on the keyboard between recording and transposing.
+    TransposeInputToggle,
+    Undo,
+    // Switch the grid to a view, or back to the steps if already there.
+    ViewToggle(View),
 }
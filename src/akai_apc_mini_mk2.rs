@@ -1,14 +1,15 @@
-use coremidi::{Destination, EventBuffer, OutputPort, Protocol};
 use itertools::Itertools;
 use lazy_static::lazy_static;
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
 use crate::{
-    action::Action,
+    action::{Action, Scope},
     akai_apc_mini_mk2_constants::AKAI_APC_MINI_MK_2_COLORS_SQUARED,
-    device::{Color, ColorStyle, Device},
+    device::{Color, ColorStyle, Device, MidiOutput},
+    effects::Effect,
     error::AppError,
-    state::PlayMode,
+    state::{ClockRate, Condition, PlayMode, View},
 };
 
 // Leftovers. I need to go through to see if these are still useful.
@@ -26,6 +27,11 @@ const _LIGHT_DIM: u32 = 0x01;
 pub const SCENE_LAUNCH_OFFSET: u32 = 0x00000070;
 pub const TRACK_OFFSET: u32 = 0x00000064;
 pub const SHIFT_BUTTON: u32 = 0x0000007a;
+// The faders are controllers 48 through 56, left to right. The last one is the
+// master fader.
+pub const FADER_OFFSET: u32 = 0x00000030;
+pub const FADER_COUNT: u32 = 9;
+pub const CONTROL_CHANGE_STATUS: u32 = 0x20b00000;
 pub const NOTE_ON_STATUS: u32 = 0x20900000;
 pub const _COLOR_INTENSITY: u32 = 0x20960000;
 pub const LED_10_BRIGHT: u32 = 0x00000000;
@@ -47,6 +53,94 @@ pub const BLINKING_1_2: u32 = 0x000f0000;
 pub const NOTE_OFF_STATUS: u32 = 0x20800000;
 pub const _BAR_BLINK: u32 = 2;
 pub const GRID_MASK: u32 = 0x0000ff00;
+pub const VELOCITY_MASK: u32 = 0x0000007f;
+pub const GRID_WIDTH: usize = 8;
+pub const GRID_HEIGHT: usize = 8;
+pub const TRACK_BUTTON_COUNT: usize = 8;
+pub const SCENE_LAUNCH_BUTTON_COUNT: usize = 8;
+
+// Functions reached by holding shift and pressing a scene launch button. Scene
+// launch buttons count from the top.
+pub const SHIFT_SCENE_UNDO: u32 = 0;
+pub const SHIFT_SCENE_REDO: u32 = 1;
+pub const SHIFT_SCENE_PAGE_OVERVIEW: u32 = 2;
+pub const SHIFT_SCENE_FOLLOW: u32 = 3;
+pub const SHIFT_SCENE_SCROLL_UP: u32 = 4;
+pub const SHIFT_SCENE_SCROLL_DOWN: u32 = 5;
+pub const SHIFT_SCENE_DRUM_MODE: u32 = 6;
+pub const SHIFT_SCENE_DIRECTION: u32 = 7;
+// Clock rates reached by holding a scene launch button and pressing a track
+// button, as (multiplier, divider). Slowest is on the left.
+pub const TRACK_CLOCK_RATES: [(usize, usize); TRACK_BUTTON_COUNT] = [
+    (1, 4),
+    (1, 3),
+    (1, 2),
+    (1, 1),
+    (3, 2),
+    (2, 1),
+    (3, 1),
+    (4, 1),
+];
+// Functions reached by holding a pad and pressing a scene launch button.
+pub const PAD_SCENE_NUDGE_EARLIER: u32 = 0;
+pub const PAD_SCENE_NUDGE_LATER: u32 = 1;
+pub const PAD_SCENE_CHORD_STAMP: u32 = 2;
+pub const PAD_SCENE_CHORD_MEMORIZE: u32 = 3;
+// Scene launch buttons from here down pick how many times the step is struck,
+// starting from once.
+pub const PAD_SCENE_RATCHETS: u32 = 4;
+// Conditions reached by holding a pad and pressing a track button, from the
+// left. Pressing the same one again goes through the choices of the kind.
+pub const PAD_TRACK_CONDITIONS: [Condition; 6] = [
+    Condition::Always,
+    Condition::Chance { percent: 0 },
+    Condition::Every { nth: 0, of: 0 },
+    Condition::Fill,
+    Condition::First,
+    Condition::NotPrevious,
+];
+// Hold a pad and press one of these track buttons to copy the step to the
+// clipboard, or paste the clipboard over it. Hold a scene launch or track
+// button and press the pad on the bottom row above the same track button to do
+// the same for a layer or a section.
+pub const CLIPBOARD_COPY: u32 = 6;
+pub const CLIPBOARD_PASTE: u32 = 7;
+// How far a single press nudges a step, as a percentage of a step.
+pub const NUDGE_AMOUNT: i32 = 10;
+// Views reached by holding shift and pressing a pad along the top row, from
+// the left.
+pub const SHIFT_TOP_ROW_VIEWS: [View; 4] = [
+    View::PageOverview,
+    View::LayerSettings,
+    View::Automation,
+    View::Keyboard,
+];
+// Views reached by holding shift and pressing a pad along the row below the
+// top, from the left. The last pads on the row tap the tempo and freeze the
+// active layer.
+pub const SHIFT_SECOND_ROW_VIEWS: [View; 4] =
+    [View::Euclid, View::Mutation, View::Follow, View::Morph];
+pub const SHIFT_SECOND_ROW_TAP: u32 = 6;
+pub const SHIFT_SECOND_ROW_FREEZE: u32 = 7;
+// Effects held down by holding shift and a pad along the third row from the
+// top, from the left. Each lasts until its pad is let go of.
+pub const SHIFT_THIRD_ROW_EFFECTS: [Effect; 8] = [
+    Effect::Fill,
+    Effect::Solo,
+    Effect::HalfTime,
+    Effect::Stutter { steps: 1 },
+    Effect::Stutter { steps: 2 },
+    Effect::Stutter { steps: 3 },
+    Effect::Stutter { steps: 4 },
+    Effect::Stutter { steps: 8 },
+];
+// The last pads on the top row arpeggiate the active layer, switch the
+// keyboard to transposing, go through the ways of recording from it, and turn
+// fills on and off.
+pub const SHIFT_TOP_ROW_ARPEGGIO: u32 = 4;
+pub const SHIFT_TOP_ROW_TRANSPOSE: u32 = 5;
+pub const SHIFT_TOP_ROW_RECORD: u32 = 6;
+pub const SHIFT_TOP_ROW_FILL: u32 = 7;
 
 lazy_static! {
     static ref COLORS_BY_VELOCITY: HashMap<u32, u32> = HashMap::from([
@@ -182,8 +276,62 @@ lazy_static! {
     ]);
 }
 
-#[derive(Clone, Copy)]
-pub struct AkaiApcMiniMk2 {}
+/**
+ * The physical buttons on the device, decoded from the note number.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Button {
+    Pad { x: u32, y: u32 },
+    Track(u32),
+    SceneLaunch(u32),
+    Shift,
+    Unknown(u32),
+}
+
+fn button_from_grid(grid: u32) -> Button {
+    if grid < 64 {
+        Button::Pad {
+            x: grid % 8,
+            y: grid / 8,
+        }
+    } else if (TRACK_OFFSET..=0x6b).contains(&grid) {
+        Button::Track(grid - TRACK_OFFSET)
+    } else if (SCENE_LAUNCH_OFFSET..SHIFT_BUTTON).contains(&grid) {
+        Button::SceneLaunch(grid - SCENE_LAUNCH_OFFSET)
+    } else if grid == SHIFT_BUTTON {
+        Button::Shift
+    } else {
+        Button::Unknown(grid)
+    }
+}
+
+struct HeldButton {
+    button: Button,
+    // Set once the button has been used as a modifier, so letting go of it
+    // doesn't also do what a plain tap would.
+    used: bool,
+    // The effect the button is holding down, if any, let go of along with it.
+    effect: Option<Effect>,
+}
+
+/**
+ * The mk2 only has so many buttons, so most functions beyond the basics are
+ * reached by holding one button down and pressing another. We keep track of
+ * what is held here.
+ */
+#[derive(Default)]
+struct HeldButtons {
+    buttons: Vec<HeldButton>,
+    // Set while the grid is played as a keyboard. Pads then play as soon as
+    // they are pressed, so they can't be held to reach anything else. Only
+    // shift still reaches things from the pads.
+    keyboard: bool,
+}
+
+#[derive(Clone, Default)]
+pub struct AkaiApcMiniMk2 {
+    held: Arc<Mutex<HeldButtons>>,
+}
 
 fn color_square(rgb: u32) -> (u32, u32, u32) {
     let r = (0xff0000 & rgb) >> 16;
@@ -246,8 +394,7 @@ fn color_style_to_u32(style: ColorStyle) -> u32 {
 }
 
 fn set_grid_button_internal(
-    output_port: &OutputPort,
-    dest: &Destination,
+    output: &dyn MidiOutput,
     x: usize,
     y: usize,
     color: Color,
@@ -258,81 +405,420 @@ fn set_grid_button_internal(
         | color_style_to_u32(color.style)
         | (x as u32 + (y as u32 * 8)) << 8
         | COLORS_BY_VELOCITY.get(&nearest).unwrap_or(&0);
-    let note_on = EventBuffer::new(Protocol::Midi10).with_packet(0, &[payload]);
-    output_port
-        .send(&dest, &note_on)
-        .map_err(AppError::OutputSendError)
+    output.send(payload)
+}
+
+// What a button does when nothing else is held.
+fn press_action(button: Button, keyboard: bool) -> Action {
+    match button {
+        Button::Pad { x, y } if keyboard => Action::KeyPress { x, y },
+        // Pads act on release, since holding one could be the start of a
+        // gesture.
+        Button::Pad { .. } => Action::Noop,
+        Button::Track(pos) => {
+            println!("Bottom button: {}", pos);
+            Action::SectionSelect { pos }
+        }
+        Button::SceneLaunch(pos) => Action::LayerSelect { pos },
+        // Shift acts on release, since it could be the start of a gesture.
+        Button::Shift => Action::Noop,
+        Button::Unknown(grid) => {
+            println!("Unsupported button {:08x}", grid);
+            Action::Noop
+        }
+    }
+}
+
+// What a button does while another button is held down. None means the
+// combination means nothing, and the press is treated as a plain press. Taps
+// of the tempo happen this many milliseconds after starting up.
+fn gesture_action(modifier: Button, button: Button, ms: u64) -> Option<Action> {
+    match (modifier, button) {
+        (Button::Shift, Button::SceneLaunch(SHIFT_SCENE_UNDO)) => {
+            Some(Action::Undo)
+        }
+        (Button::Shift, Button::SceneLaunch(SHIFT_SCENE_REDO)) => {
+            Some(Action::Redo)
+        }
+        (Button::Shift, Button::SceneLaunch(SHIFT_SCENE_PAGE_OVERVIEW)) => {
+            Some(Action::ViewToggle(View::PageOverview))
+        }
+        (Button::Shift, Button::SceneLaunch(SHIFT_SCENE_FOLLOW)) => {
+            Some(Action::FollowToggle)
+        }
+        (Button::Shift, Button::SceneLaunch(SHIFT_SCENE_SCROLL_UP)) => {
+            Some(Action::RowScroll { rows: 1 })
+        }
+        (Button::Shift, Button::SceneLaunch(SHIFT_SCENE_SCROLL_DOWN)) => {
+            Some(Action::RowScroll { rows: -1 })
+        }
+        (Button::Shift, Button::SceneLaunch(SHIFT_SCENE_DRUM_MODE)) => {
+            Some(Action::DrumModeToggle)
+        }
+        (Button::Shift, Button::SceneLaunch(SHIFT_SCENE_DIRECTION)) => {
+            Some(Action::DirectionCycle)
+        }
+        (Button::Shift, Button::Track(pos)) => Some(Action::PageSelect { pos }),
+        (
+            Button::Shift,
+            Button::Pad {
+                x: SHIFT_TOP_ROW_FILL,
+                y,
+            },
+        ) if y as usize == GRID_HEIGHT - 1 => Some(Action::FillToggle),
+        (
+            Button::Shift,
+            Button::Pad {
+                x: SHIFT_TOP_ROW_RECORD,
+                y,
+            },
+        ) if y as usize == GRID_HEIGHT - 1 => Some(Action::RecordModeCycle),
+        (
+            Button::Shift,
+            Button::Pad {
+                x: SHIFT_TOP_ROW_ARPEGGIO,
+                y,
+            },
+        ) if y as usize == GRID_HEIGHT - 1 => Some(Action::ArpeggioToggle),
+        (
+            Button::Shift,
+            Button::Pad {
+                x: SHIFT_TOP_ROW_TRANSPOSE,
+                y,
+            },
+        ) if y as usize == GRID_HEIGHT - 1 => {
+            Some(Action::TransposeInputToggle)
+        }
+        (Button::Shift, Button::Pad { x, y })
+            if y as usize == GRID_HEIGHT - 1 =>
+        {
+            SHIFT_TOP_ROW_VIEWS
+                .get(x as usize)
+                .map(|view| Action::ViewToggle(view.clone()))
+        }
+        (
+            Button::Shift,
+            Button::Pad {
+                x: SHIFT_SECOND_ROW_TAP,
+                y,
+            },
+        ) if y as usize == GRID_HEIGHT - 2 => Some(Action::TapTempo { ms }),
+        (
+            Button::Shift,
+            Button::Pad {
+                x: SHIFT_SECOND_ROW_FREEZE,
+                y,
+            },
+        ) if y as usize == GRID_HEIGHT - 2 => {
+            Some(Action::MutationFreezeToggle)
+        }
+        (Button::Shift, Button::Pad { x, y })
+            if y as usize == GRID_HEIGHT - 2 =>
+        {
+            SHIFT_SECOND_ROW_VIEWS
+                .get(x as usize)
+                .map(|view| Action::ViewToggle(view.clone()))
+        }
+        (Button::Shift, Button::Pad { x, y })
+            if y as usize == GRID_HEIGHT - 3 =>
+        {
+            SHIFT_THIRD_ROW_EFFECTS
+                .get(x as usize)
+                .map(|effect| Action::EffectPress(*effect))
+        }
+        // Hold one button and press another of the same kind to copy the first
+        // onto the second.
+        (Button::Pad { x: from, .. }, Button::Pad { x: to, .. }) => {
+            Some(Action::Duplicate {
+                from: Scope::Step { x: from },
+                to: Scope::Step { x: to },
+            })
+        }
+        (Button::SceneLaunch(from), Button::SceneLaunch(to)) => {
+            Some(Action::Duplicate {
+                from: Scope::Layer { pos: from },
+                to: Scope::Layer { pos: to },
+            })
+        }
+        (Button::Track(from), Button::Track(to)) => Some(Action::Duplicate {
+            from: Scope::Section { pos: from },
+            to: Scope::Section { pos: to },
+        }),
+        // Or go by way of the clipboard, to paste somewhere out of reach, such
+        // as a layer into another section.
+        (Button::Pad { x, .. }, Button::Track(CLIPBOARD_COPY)) => {
+            Some(Action::Copy(Scope::Step { x }))
+        }
+        (Button::Pad { x, .. }, Button::Track(CLIPBOARD_PASTE)) => {
+            Some(Action::Paste(Scope::Step { x }))
+        }
+        (
+            Button::SceneLaunch(pos),
+            Button::Pad {
+                x: CLIPBOARD_COPY,
+                y: 0,
+            },
+        ) => Some(Action::Copy(Scope::Layer { pos })),
+        (
+            Button::SceneLaunch(pos),
+            Button::Pad {
+                x: CLIPBOARD_PASTE,
+                y: 0,
+            },
+        ) => Some(Action::Paste(Scope::Layer { pos })),
+        (
+            Button::Track(pos),
+            Button::Pad {
+                x: CLIPBOARD_COPY,
+                y: 0,
+            },
+        ) => Some(Action::Copy(Scope::Section { pos })),
+        (
+            Button::Track(pos),
+            Button::Pad {
+                x: CLIPBOARD_PASTE,
+                y: 0,
+            },
+        ) => Some(Action::Paste(Scope::Section { pos })),
+        // Hold a pad and press a scene launch button to nudge the step or
+        // strike it more than once.
+        (
+            Button::Pad { x, .. },
+            Button::SceneLaunch(PAD_SCENE_NUDGE_EARLIER),
+        ) => Some(Action::StepNudge {
+            x,
+            amount: -NUDGE_AMOUNT,
+        }),
+        (Button::Pad { x, .. }, Button::SceneLaunch(PAD_SCENE_NUDGE_LATER)) => {
+            Some(Action::StepNudge {
+                x,
+                amount: NUDGE_AMOUNT,
+            })
+        }
+        (Button::Pad { x, .. }, Button::SceneLaunch(scene))
+            if scene >= PAD_SCENE_RATCHETS =>
+        {
+            Some(Action::StepRatchet {
+                x,
+                ratchets: scene - PAD_SCENE_RATCHETS + 1,
+            })
+        }
+        // Or to stamp the chord of the layer onto the step from the row of the
+        // pad, or memorize the chord on the step as the custom chord.
+        (Button::Pad { x, y }, Button::SceneLaunch(PAD_SCENE_CHORD_STAMP)) => {
+            Some(Action::ChordStamp { x, y })
+        }
+        (
+            Button::Pad { x, .. },
+            Button::SceneLaunch(PAD_SCENE_CHORD_MEMORIZE),
+        ) => Some(Action::ChordMemorize { x }),
+        // Or a track button to only play it some of the time.
+        (Button::Pad { x, .. }, Button::Track(condition)) => {
+            PAD_TRACK_CONDITIONS
+                .get(condition as usize)
+                .map(|kind| Action::StepConditionCycle { x, kind: *kind })
+        }
+        // Hold a layer's button and press a pad to end the layer on that step,
+        // or a track button to change how fast it runs. The clipboard pads
+        // along the bottom row are taken, but the rows above end it the same.
+        (Button::SceneLaunch(pos), Button::Pad { x, .. }) => {
+            Some(Action::LayerLengthChange { pos, x })
+        }
+        (Button::SceneLaunch(pos), Button::Track(rate)) => TRACK_CLOCK_RATES
+            .get(rate as usize)
+            .map(|(multiplier, divider)| Action::LayerRateChange {
+                pos,
+                rate: ClockRate {
+                    multiplier: *multiplier,
+                    divider: *divider,
+                },
+            }),
+        // Hold a button and press shift to clear it.
+        (Button::Pad { x, .. }, Button::Shift) => {
+            Some(Action::Clear(Scope::Step { x }))
+        }
+        (Button::SceneLaunch(pos), Button::Shift) => {
+            Some(Action::Clear(Scope::Layer { pos }))
+        }
+        (Button::Track(pos), Button::Shift) => {
+            Some(Action::Clear(Scope::Section { pos }))
+        }
+        _ => None,
+    }
+}
+
+// What letting go of a button does.
+fn release_action(button: Button, used: bool, keyboard: bool) -> Action {
+    match button {
+        Button::Pad { x, y } if keyboard => Action::KeyRelease { x, y },
+        Button::Pad { x, y } if !used => {
+            println!("Coords: {} {}", x, y);
+            Action::GridToggle { x, y }
+        }
+        // Tapping shift on its own plays.
+        Button::Shift if !used => {
+            println!("Changing play mode to playing");
+            Action::PlayModeChange(PlayMode::Playing)
+        }
+        _ => Action::Noop,
+    }
+}
+
+/**
+ * The controllers the master fader picks between, bottom to top: modulation,
+ * volume, pan, expression, then resonance, release, attack and cutoff.
+ */
+const AUTOMATION_LANES: [u8; 8] = [1, 7, 10, 11, 71, 72, 73, 74];
+
+// Each fader locks the controller in the automation view on the step above it,
+// whatever the view. The master fader picks which controller that is, in
+// notches so it stays put while being moved. In the Euclid and mutation views
+// the first faders shape the rhythm or the mutation instead, and in the morph
+// view the first one morphs.
+fn fader_action(fader: u32, value: u32) -> Action {
+    if fader < GRID_WIDTH as u32 {
+        Action::LockChange {
+            x: fader,
+            value: value as u8,
+        }
+    } else {
+        let notch = value as usize * AUTOMATION_LANES.len() / 128;
+        Action::AutomationLaneChange {
+            cc: AUTOMATION_LANES[notch.min(AUTOMATION_LANES.len() - 1)],
+        }
+    }
+}
+
+impl AkaiApcMiniMk2 {
+    fn button_down(&self, button: Button, ms: u64) -> Action {
+        match self.held.lock() {
+            Ok(mut held) => {
+                let keyboard = held.keyboard;
+                let is_pad = |x: Button| matches!(x, Button::Pad { .. });
+                let gesture = held
+                    .buttons
+                    .iter_mut()
+                    .find(|modifier| !keyboard || !is_pad(modifier.button))
+                    .filter(|modifier| {
+                        !keyboard
+                            || !is_pad(button)
+                            || modifier.button == Button::Shift
+                    })
+                    .and_then(|modifier| {
+                        gesture_action(modifier.button, button, ms)
+                            .inspect(|_| modifier.used = true)
+                    });
+                let effect = match gesture {
+                    Some(Action::EffectPress(effect)) => Some(effect),
+                    _ => None,
+                };
+                held.buttons.push(HeldButton {
+                    button,
+                    used: gesture.is_some(),
+                    effect,
+                });
+                gesture.unwrap_or_else(|| press_action(button, keyboard))
+            }
+            Err(_) => press_action(button, false),
+        }
+    }
+
+    fn button_up(&self, button: Button) -> Action {
+        match self.held.lock() {
+            Ok(mut held) => {
+                match held.buttons.iter().position(|x| x.button == button) {
+                    Some(index) => {
+                        let released = held.buttons.remove(index);
+                        match released.effect {
+                            Some(effect) => Action::EffectRelease(effect),
+                            None => release_action(
+                                released.button,
+                                released.used,
+                                held.keyboard,
+                            ),
+                        }
+                    }
+                    None => Action::Noop,
+                }
+            }
+            Err(_) => Action::Noop,
+        }
+    }
 }
 
 impl Device for AkaiApcMiniMk2 {
-    fn midi_to_action(&self, _context: u32, data: u32) -> Action {
+    fn midi_to_action(&self, _context: u32, data: u32, ms: u64) -> Action {
         let command = data >> 20;
-        if command == (NOTE_ON_STATUS >> 20) {
+        let button = button_from_grid((GRID_MASK & data) >> 8);
+        // A note on with no velocity is a note off in disguise.
+        if command == (NOTE_ON_STATUS >> 20) && data & VELOCITY_MASK > 0 {
             println!("Note on {:08x}", command);
-            let grid = (GRID_MASK & data) >> 8;
-            println!("Grid: {:08x}", grid);
-            if grid < 64 {
-                let x = grid % 8;
-                let y = grid / 8;
-                println!("Coords: {} {}", x, y);
-                Action::GridToggle { x, y }
-            } else if grid >= TRACK_OFFSET && grid <= 0x6b {
-                let bottom_button = grid - TRACK_OFFSET;
-                println!("Bottom button: {}", bottom_button);
-                Action::SectionSelect { pos: bottom_button }
-            } else if grid >= SCENE_LAUNCH_OFFSET && grid < SHIFT_BUTTON {
-                let scene_launch_button = grid - SCENE_LAUNCH_OFFSET;
-                // println!("Scene launch button: {}", scene_launch_button);
-                Action::LayerSelect {
-                    pos: scene_launch_button,
+            self.button_down(button, ms)
+        } else if command == (NOTE_ON_STATUS >> 20)
+            || command == (NOTE_OFF_STATUS >> 20)
+        {
+            println!("Note off {:08x}", command);
+            self.button_up(button)
+        } else if command == (CONTROL_CHANGE_STATUS >> 20) {
+            let controller = (GRID_MASK & data) >> 8;
+            match controller.checked_sub(FADER_OFFSET) {
+                Some(fader) if fader < FADER_COUNT => {
+                    fader_action(fader, data & VELOCITY_MASK)
+                }
+                _ => {
+                    println!("Unsupported controller {:08x}", controller);
+                    Action::Noop
                 }
-            } else if grid == SHIFT_BUTTON {
-                println!("Changing play mode to playing");
-                Action::PlayModeChange(PlayMode::Playing)
-            } else {
-                println!("Unsupported message {:08x}", command);
-                Action::Noop
             }
-        } else if command == (NOTE_OFF_STATUS >> 20) {
-            println!("Note off {:08x}", command);
-            Action::Noop
         } else {
             println!("Unsupported message {:08x}", command);
             Action::Noop
         }
     }
 
+    fn set_grid_keyboard(&self, keyboard: bool) {
+        if let Ok(mut held) = self.held.lock() {
+            held.keyboard = keyboard;
+        }
+    }
+
+    fn grid_width(&self) -> usize {
+        GRID_WIDTH
+    }
+
+    fn grid_height(&self) -> usize {
+        GRID_HEIGHT
+    }
+
+    // Scene launch buttons pick the layer.
+    fn layer_button_count(&self) -> usize {
+        SCENE_LAUNCH_BUTTON_COUNT
+    }
+
+    // Track buttons pick the section.
+    fn section_button_count(&self) -> usize {
+        TRACK_BUTTON_COUNT
+    }
+
     fn set_grid_button(
         &self,
-        output_port: &OutputPort,
-        dest: &Destination,
+        output: &dyn MidiOutput,
         x: usize,
         y: usize,
         color: Color,
     ) -> Result<(), AppError> {
-        set_grid_button_internal(
-            &output_port,
-            &dest,
-            x,
-            y,
-            Color {
-                rgb: color.rgb,
-                style: ColorStyle::Steady75,
-            },
-        )
+        set_grid_button_internal(output, x, y, color)
     }
 
     fn set_interval(
         &self,
-        output_port: &OutputPort,
-        dest: &Destination,
+        output: &dyn MidiOutput,
         x: usize,
         y: usize,
         color: Color,
     ) -> Result<(), AppError> {
         set_grid_button_internal(
-            &output_port,
-            &dest,
+            output,
             x,
             y,
             Color {
@@ -344,8 +830,7 @@ impl Device for AkaiApcMiniMk2 {
 
     fn set_layer_button(
         &self,
-        output_port: &OutputPort,
-        dest: &Destination,
+        output: &dyn MidiOutput,
         layer_index: usize,
         color: Color,
     ) -> Result<(), AppError> {
@@ -354,17 +839,12 @@ impl Device for AkaiApcMiniMk2 {
             | (SCENE_LAUNCH_OFFSET + layer_index as u32) << 8
             | color.rgb;
         // println!("Setting Layer button {} to color {:08x} as payload {:08x}", layer_index, color.rgb, payload);
-        let event =
-            EventBuffer::new(Protocol::Midi10).with_packet(0, &[payload]);
-        output_port
-            .send(&dest, &event)
-            .map_err(AppError::OutputSendError)
+        output.send(payload)
     }
 
     fn set_play_button(
         &self,
-        output_port: &OutputPort,
-        dest: &Destination,
+        output: &dyn MidiOutput,
         color: Color,
     ) -> Result<(), AppError> {
         // Always use NoteOn even though we turn off buttons this way.
@@ -373,17 +853,12 @@ impl Device for AkaiApcMiniMk2 {
             "Setting play button to color {:08x} as payload {:08x}",
             color.rgb, payload,
         );
-        let event =
-            EventBuffer::new(Protocol::Midi10).with_packet(0, &[payload]);
-        output_port
-            .send(&dest, &event)
-            .map_err(AppError::OutputSendError)
+        output.send(payload)
     }
 
     fn set_section_button(
         &self,
-        output_port: &OutputPort,
-        dest: &Destination,
+        output: &dyn MidiOutput,
         section_index: usize,
         color: Color,
     ) -> Result<(), AppError> {
@@ -392,10 +867,119 @@ impl Device for AkaiApcMiniMk2 {
             | (TRACK_OFFSET + section_index as u32) << 8
             | color.rgb;
         // println!("Setting section button {} to color {:08x} as payload {:08x}", section_index, color.rgb, payload);
-        let event =
-            EventBuffer::new(Protocol::Midi10).with_packet(0, &[payload]);
-        output_port
-            .send(&dest, &event)
-            .map_err(AppError::OutputSendError)
+        output.send(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::state_to_device;
+    use crate::state::{initial_state, GlobalState, Note, Settings};
+    use std::cell::RefCell;
+
+    #[derive(Default)]
+    struct Sent {
+        packets: RefCell<Vec<u32>>,
+    }
+
+    impl MidiOutput for Sent {
+        fn send(&self, packet: u32) -> Result<(), AppError> {
+            self.packets.borrow_mut().push(packet);
+            Ok(())
+        }
+    }
+
+    // The last packet sent to pad x, y when the state is put on the grid.
+    fn pad_packet(state: &GlobalState, x: usize, y: usize) -> u32 {
+        let output = Sent::default();
+        state_to_device(&AkaiApcMiniMk2::default(), &output, state).unwrap();
+        let pad = (x + y * GRID_WIDTH) as u32;
+        output
+            .packets
+            .into_inner()
+            .into_iter()
+            .rev()
+            .find(|packet| packet & 0xff00 == pad << 8)
+            .unwrap()
+    }
+
+    fn brightness(packet: u32) -> u32 {
+        packet & 0x000f0000
+    }
+
+    #[test]
+    fn roots_are_brighter_than_the_rest_of_the_scale() {
+        let mut state = initial_state(Settings::default());
+        state.player.view = View::Keyboard;
+        // The keyboard starts from C, the root, and goes up the scale.
+        let root = pad_packet(&state, 0, 0);
+        let second = pad_packet(&state, 1, 0);
+        assert_eq!(root & 0xff, second & 0xff);
+        assert_eq!(brightness(root), LED_75_BRIGHT);
+        assert_eq!(brightness(second), LED_25_BRIGHT);
+    }
+
+    #[test]
+    fn the_edge_is_dimmer_than_the_notes_on_it() {
+        let mut state = initial_state(Settings::default());
+        // The grid shows rows 60 to 67, and the third step has a note on the
+        // top row as well as one far above it.
+        state.sections[0].layers[0].notes[2] = Note {
+            octaves: vec![67, 100],
+            length: 1,
+            ..Note::default()
+        };
+        let note = pad_packet(&state, 2, 7);
+        let edge = pad_packet(&state, 3, 7);
+        assert_eq!(brightness(edge), LED_10_BRIGHT);
+        assert_ne!(brightness(note), LED_10_BRIGHT);
+        // Nothing is below the grid, so the bottom row stays dark.
+        assert_eq!(pad_packet(&state, 3, 0) & 0xff, 0);
+    }
+
+    // The actions for a run of presses (true) and releases (false).
+    fn actions(buttons: &[(u32, bool)]) -> Vec<Action> {
+        let device = AkaiApcMiniMk2::default();
+        buttons
+            .iter()
+            .map(|(button, down)| {
+                let status = if *down {
+                    NOTE_ON_STATUS
+                } else {
+                    NOTE_OFF_STATUS
+                };
+                device.midi_to_action(
+                    0,
+                    status | button << 8 | VELOCITY_MASK,
+                    0,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tapping_shift_plays() {
+        let tapped = actions(&[(SHIFT_BUTTON, true), (SHIFT_BUTTON, false)]);
+        assert!(matches!(tapped[0], Action::Noop));
+        assert!(matches!(
+            tapped[1],
+            Action::PlayModeChange(PlayMode::Playing)
+        ));
+    }
+
+    #[test]
+    fn holding_shift_for_a_gesture_does_not_play() {
+        let undo = SCENE_LAUNCH_OFFSET + SHIFT_SCENE_UNDO;
+        let held = actions(&[
+            (SHIFT_BUTTON, true),
+            (undo, true),
+            (undo, false),
+            (SHIFT_BUTTON, false),
+        ]);
+        assert!(matches!(held[1], Action::Undo));
+        assert!(!held
+            .iter()
+            .any(|action| matches!(action, Action::PlayModeChange(_))));
     }
 }
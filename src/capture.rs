@@ -0,0 +1,326 @@
+use crate::action::Action;
+use crate::device::{follow_view, ChangedOutput, Device, MidiOutput};
+use crate::error::AppError;
+use crate::history::{self, Undoable};
+use crate::render::state_to_device;
+use crate::state::{initial_state, Settings};
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/**
+ * Everything that goes in or out of the controller. Ticks of the clock are
+ * kept too, since they change what gets drawn on the grid.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CaptureEvent {
+    In(u32),
+    Out(u32),
+    Tick,
+}
+
+/**
+ * A Capture is a recording of the raw MIDI traffic between us and the
+ * controller. One event per line, like so:
+ *
+ *   <elapsed ms> in 20907f7f
+ *   <elapsed ms> out 20960005
+ *   <elapsed ms> ticks 12
+ *
+ * Ticks of the clock are counted up and written before whatever comes after
+ * them, rather than one to a line.
+ *
+ * Captures are expected to start from a blank slate (no replayed journal), so
+ * they can be checked against later with check_capture. They must be checked
+ * with the same settings they were made with.
+ */
+pub struct Capture {
+    file: File,
+    started: Instant,
+    // Ticks of the clock not yet written.
+    ticks: usize,
+}
+
+impl Capture {
+    pub fn create(path: &Path) -> Result<Capture, AppError> {
+        File::create(path)
+            .map(|file| Capture {
+                file,
+                started: Instant::now(),
+                ticks: 0,
+            })
+            .map_err(AppError::CaptureWriteError)
+    }
+
+    pub fn record(&mut self, event: CaptureEvent) -> Result<(), AppError> {
+        let elapsed_ms = self.started.elapsed().as_millis();
+        if event == CaptureEvent::Tick {
+            self.ticks += 1;
+            return Ok(());
+        }
+        if self.ticks > 0 {
+            writeln!(self.file, "{} ticks {}", elapsed_ms, self.ticks)
+                .map_err(AppError::CaptureWriteError)?;
+            self.ticks = 0;
+        }
+        match event {
+            CaptureEvent::In(packet) => {
+                writeln!(self.file, "{} in {:08x}", elapsed_ms, packet)
+            }
+            CaptureEvent::Out(packet) => {
+                writeln!(self.file, "{} out {:08x}", elapsed_ms, packet)
+            }
+            CaptureEvent::Tick => Ok(()),
+        }
+        .map_err(AppError::CaptureWriteError)
+    }
+}
+
+pub fn capture_event(
+    capture: &Option<Arc<Mutex<Capture>>>,
+    event: CaptureEvent,
+) {
+    if let Some(capture_mutex) = capture {
+        if let Ok(mut capture) = capture_mutex.lock() {
+            capture.record(event).unwrap_or_else(|err| {
+                println!("Error writing to capture: {:#?}", err);
+            })
+        }
+    }
+}
+
+/**
+ * Records everything sent out before passing it along.
+ */
+pub struct CaptureOutput {
+    pub inner: Box<dyn MidiOutput + Send>,
+    pub capture: Option<Arc<Mutex<Capture>>>,
+}
+
+impl MidiOutput for CaptureOutput {
+    fn send(&self, packet: u32) -> Result<(), AppError> {
+        capture_event(&self.capture, CaptureEvent::Out(packet));
+        self.inner.send(packet)
+    }
+}
+
+/**
+ * Holds onto everything sent to it, for when there is no controller around.
+ */
+#[derive(Default)]
+struct BufferOutput {
+    packets: RefCell<Vec<u32>>,
+}
+
+impl MidiOutput for BufferOutput {
+    fn send(&self, packet: u32) -> Result<(), AppError> {
+        self.packets.borrow_mut().push(packet);
+        Ok(())
+    }
+}
+
+// Each line is an event, and when it happened in milliseconds after the
+// capture started.
+fn parse_line(line: &str) -> Result<(u64, Vec<CaptureEvent>), AppError> {
+    let parse_error = || AppError::CaptureParseError(line.to_string());
+    let parse_packet = |packet: Option<&str>| {
+        packet
+            .and_then(|x| u32::from_str_radix(x, 16).ok())
+            .ok_or_else(parse_error)
+    };
+    let mut words = line.split_whitespace();
+    let elapsed_ms = words
+        .next()
+        .and_then(|x| x.parse::<u64>().ok())
+        .ok_or_else(parse_error)?;
+    let events = match words.next() {
+        Some("in") => {
+            parse_packet(words.next()).map(|x| vec![CaptureEvent::In(x)])
+        }
+        Some("out") => {
+            parse_packet(words.next()).map(|x| vec![CaptureEvent::Out(x)])
+        }
+        Some("ticks") => words
+            .next()
+            .and_then(|x| x.parse::<usize>().ok())
+            .map(|ticks| vec![CaptureEvent::Tick; ticks])
+            .ok_or_else(parse_error),
+        _ => Err(parse_error()),
+    }?;
+    Ok((elapsed_ms, events))
+}
+
+/**
+ * Every event in a capture, with when it happened.
+ */
+pub fn read_capture(path: &Path) -> Result<Vec<(u64, CaptureEvent)>, AppError> {
+    let file = File::open(path).map_err(AppError::CaptureReadError)?;
+    BufReader::new(file)
+        .lines()
+        .filter(|line| line.as_ref().map_or(true, |x| !x.trim().is_empty()))
+        .map(|line| {
+            line.map_err(AppError::CaptureReadError)
+                .and_then(|x| parse_line(&x))
+        })
+        .collect::<Result<Vec<(u64, Vec<CaptureEvent>)>, AppError>>()
+        .map(|lines| {
+            lines
+                .into_iter()
+                .flat_map(|(elapsed_ms, events)| {
+                    events.into_iter().map(move |event| (elapsed_ms, event))
+                })
+                .collect()
+        })
+}
+
+// Feed the captured input back through the device and the reducer, with no
+// controller attached, as it came in. Hands back where that leaves the state
+// and everything that would have been sent to the controller.
+fn replay_capture(
+    device: &dyn Device,
+    settings: Settings,
+    events: &[(u64, CaptureEvent)],
+) -> Result<(Undoable, Vec<u32>), AppError> {
+    let output = ChangedOutput::new(BufferOutput::default());
+    let mut undoable = Undoable::new(initial_state(settings));
+    state_to_device(device, &output, &undoable.state)?;
+    output.flush()?;
+    for (elapsed_ms, event) in events.iter() {
+        let action = match event {
+            CaptureEvent::In(packet) => {
+                device.midi_to_action(0, *packet, *elapsed_ms)
+            }
+            CaptureEvent::Tick => Action::TimeInterval,
+            CaptureEvent::Out(_) => continue,
+        };
+        undoable = history::reducer(undoable, action);
+        follow_view(device, &undoable.state.player.view);
+        state_to_device(device, &output, &undoable.state)?;
+        output.flush()?;
+    }
+    Ok((undoable, output.inner.packets.into_inner()))
+}
+
+/**
+ * Replay the captured input and compare what we would have sent to the
+ * controller with what we actually sent when the capture was made. Any
+ * difference is printed and reported as an error.
+ */
+pub fn check_capture(
+    device: &dyn Device,
+    settings: Settings,
+    events: Vec<(u64, CaptureEvent)>,
+) -> Result<(), AppError> {
+    let (_, produced) = replay_capture(device, settings, &events)?;
+    let expected = events
+        .iter()
+        .filter_map(|(_, event)| match event {
+            CaptureEvent::Out(packet) => Some(*packet),
+            _ => None,
+        })
+        .collect::<Vec<u32>>();
+    let mismatches = (0..expected.len().max(produced.len()))
+        .filter(|i| expected.get(*i) != produced.get(*i))
+        .inspect(|i| {
+            println!(
+                "Packet {}: expected {}, produced {}",
+                i,
+                expected.get(*i).map_or("nothing".to_string(), |x| {
+                    format!("{:08x}", x)
+                }),
+                produced.get(*i).map_or("nothing".to_string(), |x| {
+                    format!("{:08x}", x)
+                }),
+            )
+        })
+        .count();
+    println!(
+        "Checked {} packets against {} captured packets.",
+        produced.len(),
+        expected.len(),
+    );
+    if mismatches == 0 {
+        Ok(())
+    } else {
+        Err(AppError::CaptureMismatchError(mismatches))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::akai_apc_mini_mk2::{
+        AkaiApcMiniMk2, NOTE_ON_STATUS, SHIFT_BUTTON, SHIFT_SECOND_ROW_TAP,
+        VELOCITY_MASK,
+    };
+    use std::fs;
+    use std::path::PathBuf;
+
+    // The settings the controller starts with when nothing else is asked for.
+    fn device_settings(device: &AkaiApcMiniMk2) -> Settings {
+        Settings {
+            page_length: device.grid_width(),
+            visible_rows: device.grid_height(),
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn ticks_are_read_back_one_at_a_time() {
+        assert_eq!(
+            parse_line("120 ticks 3").unwrap(),
+            (120, vec![CaptureEvent::Tick; 3])
+        );
+        assert_eq!(
+            parse_line("5 out 20960005").unwrap(),
+            (5, vec![CaptureEvent::Out(0x20960005)]),
+        );
+        assert!(parse_line("5 ticks many").is_err());
+        assert!(parse_line("soon in 20960005").is_err());
+    }
+
+    // The captures kept with the source are generated snapshots, not
+    // recordings from a controller. Scripted presses and ticks were run
+    // through the build of the time, and what it sent was kept. They show up
+    // any change to what is sent, so one meant to be made needs them made
+    // again. They were made with the settings the controller starts with.
+    #[test]
+    fn captures_replay_as_they_were_made() {
+        let dir =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/captures");
+        let paths = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<PathBuf>>();
+        assert!(!paths.is_empty());
+        for path in paths {
+            let device = AkaiApcMiniMk2::default();
+            let settings = device_settings(&device);
+            check_capture(&device, settings, read_capture(&path).unwrap())
+                .unwrap_or_else(|err| {
+                    panic!("{} no longer replays: {:?}", path.display(), err)
+                });
+        }
+    }
+
+    #[test]
+    fn taps_go_by_when_they_were_captured() {
+        let device = AkaiApcMiniMk2::default();
+        let press = |button: u32| {
+            CaptureEvent::In(NOTE_ON_STATUS | button << 8 | VELOCITY_MASK)
+        };
+        let tap = SHIFT_SECOND_ROW_TAP + (device.grid_height() as u32 - 2) * 8;
+        let events = [
+            (0, press(SHIFT_BUTTON)),
+            (1000, press(tap)),
+            (1500, press(tap)),
+            (2000, press(tap)),
+        ];
+        let (undoable, _) =
+            replay_capture(&device, device_settings(&device), &events).unwrap();
+        assert_eq!(undoable.state.player.tempo.bpm, 120.0);
+    }
+}
@@ -0,0 +1,77 @@
+use serde::{Deserialize, Serialize};
+
+/**
+ * Chords that can be stamped onto a step, as rows above the row pressed. The
+ * custom chord is whichever one the layer last memorized from a step.
+ */
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum Chord {
+    #[default]
+    Major,
+    Minor,
+    Sus2,
+    Sus4,
+    Major7,
+    Minor7,
+    Dominant7,
+    Custom,
+}
+
+pub const CHORDS: [Chord; 8] = [
+    Chord::Major,
+    Chord::Minor,
+    Chord::Sus2,
+    Chord::Sus4,
+    Chord::Major7,
+    Chord::Minor7,
+    Chord::Dominant7,
+    Chord::Custom,
+];
+
+impl Chord {
+    // Rows above the lowest note of the chord, which is always there.
+    pub fn intervals(&self, memory: &[usize]) -> Vec<usize> {
+        match self {
+            Chord::Major => vec![0, 4, 7],
+            Chord::Minor => vec![0, 3, 7],
+            Chord::Sus2 => vec![0, 2, 7],
+            Chord::Sus4 => vec![0, 5, 7],
+            Chord::Major7 => vec![0, 4, 7, 11],
+            Chord::Minor7 => vec![0, 3, 7, 10],
+            Chord::Dominant7 => vec![0, 4, 7, 10],
+            Chord::Custom if memory.is_empty() => vec![0],
+            Chord::Custom => memory.to_vec(),
+        }
+    }
+}
+
+// The chord on a step, as rows above its lowest one, ready to be memorized.
+pub fn memorize(octaves: &[usize]) -> Option<Vec<usize>> {
+    let lowest = *octaves.iter().min()?;
+    let mut intervals = octaves
+        .iter()
+        .map(|row| row - lowest)
+        .collect::<Vec<usize>>();
+    intervals.sort();
+    intervals.dedup();
+    Some(intervals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chords_are_memorized_from_their_lowest_row() {
+        assert_eq!(memorize(&[67, 60, 64, 60]), Some(vec![0, 4, 7]));
+        assert_eq!(memorize(&[]), None);
+    }
+
+    #[test]
+    fn the_custom_chord_is_whatever_was_memorized() {
+        let memory = memorize(&[62, 65, 69, 72]).unwrap();
+        assert_eq!(Chord::Custom.intervals(&memory), vec![0, 3, 7, 10]);
+        assert_eq!(Chord::Custom.intervals(&[]), vec![0]);
+        assert_eq!(Chord::Major.intervals(&memory), vec![0, 4, 7]);
+    }
+}
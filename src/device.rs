@@ -1,6 +1,6 @@
-use coremidi::{Destination, OutputPort};
-
-use crate::{action::Action, error::AppError};
+use crate::{action::Action, error::AppError, state::View};
+use std::collections::HashMap;
+use std::sync::Mutex;
 
 // TODO: This should be part of the concrete device.
 pub enum ColorStyle {
@@ -27,13 +27,102 @@ pub struct Color {
     pub style: ColorStyle,
 }
 
+/**
+ * Somewhere to send MIDI packets. Usually this is a port connected to the
+ * controller, but it could just as well be a capture file or a buffer when
+ * running without a controller at all.
+ */
+pub trait MidiOutput {
+    fn send(&self, packet: u32) -> Result<(), AppError>;
+}
+
+// The grid plays as a keyboard in the keyboard view. The device has to know as
+// soon as the view changes, since the very next press may be a key.
+pub fn follow_view(device: &dyn Device, view: &View) {
+    device.set_grid_keyboard(*view == View::Keyboard);
+}
+
+// The part of a packet that says what it sets, such as a pad or a button
+// light: its status, less the channel, and its note.
+const PACKET_ADDRESS_MASK: u32 = 0xfff0ff00;
+
+/**
+ * The whole device is drawn over every time the state changes, which is at
+ * least every tick of the clock. Most of it is the same as last time, so this
+ * holds onto a frame as it is drawn and passes along only what changed once
+ * it is flushed. Only the last packet of a frame to each address counts.
+ */
+pub struct ChangedOutput<O: MidiOutput> {
+    pub inner: O,
+    frame: Mutex<Vec<u32>>,
+    shown: Mutex<HashMap<u32, u32>>,
+}
+
+impl<O: MidiOutput> ChangedOutput<O> {
+    pub fn new(inner: O) -> ChangedOutput<O> {
+        ChangedOutput {
+            inner,
+            frame: Mutex::new(vec![]),
+            shown: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn flush(&self) -> Result<(), AppError> {
+        let frame = match self.frame.lock() {
+            Ok(mut frame) => std::mem::take(&mut *frame),
+            Err(_) => vec![],
+        };
+        let mut last = HashMap::new();
+        for packet in frame.iter() {
+            last.insert(packet & PACKET_ADDRESS_MASK, *packet);
+        }
+        match self.shown.lock() {
+            Ok(mut shown) => frame
+                .iter()
+                .filter_map(|packet| {
+                    let address = packet & PACKET_ADDRESS_MASK;
+                    last.remove(&address)
+                        .filter(|x| shown.get(&address) != Some(x))
+                        .inspect(|x| {
+                            shown.insert(address, *x);
+                        })
+                })
+                .try_for_each(|packet| self.inner.send(packet)),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+impl<O: MidiOutput> MidiOutput for ChangedOutput<O> {
+    fn send(&self, packet: u32) -> Result<(), AppError> {
+        if let Ok(mut frame) = self.frame.lock() {
+            frame.push(packet);
+        }
+        Ok(())
+    }
+}
+
 pub trait Device {
-    fn midi_to_action(&self, context: u32, packet: u32) -> Action;
+    // What a packet from the device does, given how many milliseconds after
+    // starting up it came in. Anything timed by hand, such as tapping the
+    // tempo, goes by that rather than the clock on the wall, so a capture
+    // replays the way it was played.
+    fn midi_to_action(&self, context: u32, packet: u32, ms: u64) -> Action;
+
+    // Play the pads as keys, sending a press and a release for each, rather
+    // than acting on them once they are let go of.
+    fn set_grid_keyboard(&self, keyboard: bool);
+
+    // How much of the project the device can show at once. Anything past these
+    // is simply not drawn.
+    fn grid_width(&self) -> usize;
+    fn grid_height(&self) -> usize;
+    fn layer_button_count(&self) -> usize;
+    fn section_button_count(&self) -> usize;
 
     fn set_grid_button(
         &self,
-        output_port: &OutputPort,
-        dest: &Destination,
+        output: &dyn MidiOutput,
         x: usize,
         y: usize,
         color: Color,
@@ -41,8 +130,7 @@ pub trait Device {
 
     fn set_interval(
         &self,
-        output_port: &OutputPort,
-        dest: &Destination,
+        output: &dyn MidiOutput,
         x: usize,
         y: usize,
         color: Color,
@@ -50,24 +138,57 @@ pub trait Device {
 
     fn set_layer_button(
         &self,
-        output_port: &OutputPort,
-        dest: &Destination,
+        output: &dyn MidiOutput,
         layer: usize,
         color: Color,
     ) -> Result<(), AppError>;
 
     fn set_play_button(
         &self,
-        output_port: &OutputPort,
-        dest: &Destination,
+        output: &dyn MidiOutput,
         color: Color,
     ) -> Result<(), AppError>;
 
     fn set_section_button(
         &self,
-        output_port: &OutputPort,
-        dest: &Destination,
+        output: &dyn MidiOutput,
         section_index: usize,
         color: Color,
     ) -> Result<(), AppError>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[derive(Default)]
+    struct Sent {
+        packets: RefCell<Vec<u32>>,
+    }
+
+    impl MidiOutput for Sent {
+        fn send(&self, packet: u32) -> Result<(), AppError> {
+            self.packets.borrow_mut().push(packet);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn only_what_changed_is_sent() {
+        let output = ChangedOutput::new(Sent::default());
+        let frame = |packets: &[u32]| {
+            packets.iter().for_each(|x| output.send(*x).unwrap());
+            output.flush().unwrap();
+        };
+        frame(&[0x20960005, 0x20960105]);
+        frame(&[0x20960005, 0x20960109]);
+        // Only the last packet to a pad in a frame counts, whatever its
+        // channel, and here it is as it was.
+        frame(&[0x20900009, 0x20960005]);
+        assert_eq!(
+            output.inner.packets.into_inner(),
+            vec![0x20960005, 0x20960105, 0x20960109],
+        );
+    }
+}
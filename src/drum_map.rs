@@ -0,0 +1,23 @@
+use crate::error::AppError;
+use crate::state::DrumVoice;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+/**
+ * Drum layers bind their rows to the General MIDI drum map out of the box. Kits
+ * that put their voices elsewhere can be given a drum map of their own, read
+ * from JSON, starting from the bottom row, like so:
+ *
+ *   [
+ *     {"name": "Kick", "pitch": 36, "color": 16711680},
+ *     {"name": "Snare", "pitch": 40, "color": 16755200}
+ *   ]
+ *
+ * Colors are RGB, written out as a number since JSON has no hex.
+ */
+pub fn read_drum_map(path: &Path) -> Result<Vec<DrumVoice>, AppError> {
+    let file = File::open(path).map_err(AppError::DrumMapReadError)?;
+    serde_json::from_reader(BufReader::new(file))
+        .map_err(AppError::DrumMapParseError)
+}
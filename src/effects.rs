@@ -0,0 +1,150 @@
+use crate::playback::TICKS_PER_STEP;
+use crate::state::Player;
+use serde::{Deserialize, Serialize};
+
+/**
+ * Effects are held down over playback, and let go of to go back to the pattern
+ * as it is. None of them change the pattern itself.
+ */
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Effect {
+    // Steps only played during a fill are played, as with a fill toggled on.
+    Fill,
+    // Only the active layer is played.
+    Solo,
+    // Everything plays at half speed, from where the effect was pressed.
+    HalfTime,
+    // The last few steps, up to and including the one playing, repeat.
+    Stutter { steps: usize },
+}
+
+/**
+ * An effect being held, and the interval it was pressed on.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HeldEffect {
+    pub effect: Effect,
+    pub interval: usize,
+}
+
+pub fn is_held(player: &Player, effect: Effect) -> bool {
+    player.effects.iter().any(|held| held.effect == effect)
+}
+
+pub fn is_fill(player: &Player) -> bool {
+    player.fill || is_held(player, Effect::Fill)
+}
+
+// Both of these move playback around in time, and can't sensibly be stacked,
+// so the last of them pressed wins.
+fn time_effect(player: &Player) -> Option<&HeldEffect> {
+    player.effects.iter().rev().find(|held| match held.effect {
+        Effect::HalfTime | Effect::Stutter { .. } => true,
+        Effect::Fill | Effect::Solo => false,
+    })
+}
+
+/**
+ * Where playback is while effects are held, for the clock at some interval. The
+ * clock runs on underneath them, so letting go picks the pattern up where it
+ * would have been anyway.
+ */
+pub fn played_interval(player: &Player, interval: usize) -> usize {
+    match time_effect(player) {
+        Some(HeldEffect {
+            effect: Effect::HalfTime,
+            interval: pressed,
+        }) => pressed + interval.saturating_sub(*pressed) / 2,
+        Some(HeldEffect {
+            effect: Effect::Stutter { steps },
+            interval: pressed,
+        }) => {
+            // The loop ends where the step playing when it was pressed ends.
+            let end = (pressed / TICKS_PER_STEP + 1) * TICKS_PER_STEP;
+            let start = end.saturating_sub((*steps).max(1) * TICKS_PER_STEP);
+            start + interval.saturating_sub(start) % (end - start)
+        }
+        Some(HeldEffect {
+            effect: Effect::Fill | Effect::Solo,
+            ..
+        })
+        | None => interval,
+    }
+}
+
+// At half time only every other tick of the clock moves playback along, and
+// everything played lasts twice as long.
+pub fn is_played(player: &Player, interval: usize) -> bool {
+    match time_effect(player) {
+        Some(HeldEffect {
+            effect: Effect::HalfTime,
+            interval: pressed,
+        }) => interval.saturating_sub(*pressed).is_multiple_of(2),
+        Some(_) | None => true,
+    }
+}
+
+pub fn stretch(player: &Player) -> usize {
+    match time_effect(player) {
+        Some(HeldEffect {
+            effect: Effect::HalfTime,
+            ..
+        }) => 2,
+        Some(_) | None => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{initial_state, Settings};
+
+    fn holding(effects: &[(Effect, usize)]) -> Player {
+        let mut player = initial_state(Settings::default()).player;
+        player.effects = effects
+            .iter()
+            .map(|(effect, interval)| HeldEffect {
+                effect: *effect,
+                interval: *interval,
+            })
+            .collect();
+        player
+    }
+
+    #[test]
+    fn half_time_plays_every_other_tick_from_the_press() {
+        let player = holding(&[(Effect::HalfTime, 10)]);
+        assert_eq!(played_interval(&player, 10), 10);
+        assert_eq!(played_interval(&player, 14), 12);
+        assert!(is_played(&player, 12));
+        assert!(!is_played(&player, 13));
+        assert_eq!(stretch(&player), 2);
+    }
+
+    #[test]
+    fn stutters_repeat_up_to_the_end_of_the_step_pressed_on() {
+        let steps = 2;
+        let pressed = 3 * TICKS_PER_STEP + 5;
+        let player = holding(&[(Effect::Stutter { steps }, pressed)]);
+        let start = 2 * TICKS_PER_STEP;
+        assert_eq!(played_interval(&player, pressed), pressed);
+        assert_eq!(played_interval(&player, 4 * TICKS_PER_STEP), start);
+        assert_eq!(
+            played_interval(&player, 5 * TICKS_PER_STEP + 1),
+            start + TICKS_PER_STEP + 1
+        );
+    }
+
+    #[test]
+    fn the_last_time_effect_pressed_wins() {
+        let player = holding(&[
+            (Effect::HalfTime, 0),
+            (Effect::Fill, 0),
+            (Effect::Stutter { steps: 1 }, 0),
+        ]);
+        assert_eq!(played_interval(&player, TICKS_PER_STEP), 0);
+        assert_eq!(stretch(&player), 1);
+        assert!(is_fill(&player));
+        assert!(!is_held(&player, Effect::Solo));
+    }
+}
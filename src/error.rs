@@ -1,12 +1,33 @@
 #[derive(Debug)]
 pub enum AppError {
+    CaptureMismatchError(usize),
+    CaptureParseError(String),
+    CaptureReadError(std::io::Error),
+    CaptureWriteError(std::io::Error),
+    ConflictingOptionsError(String, String),
     DestinationNotFoundError,
     DisplayNameError,
+    DrumMapParseError(serde_json::Error),
+    DrumMapReadError(std::io::Error),
+    GrooveParseError(serde_json::Error),
+    GrooveReadError(std::io::Error),
+    InstrumentNotFoundError(String),
+    InvalidOptionValueError(String, String),
+    JournalParseError(serde_json::Error),
+    JournalReadError(std::io::Error),
+    JournalSessionError,
+    JournalWriteError(std::io::Error),
+    KeyboardNotFoundError(String),
     MidiClientError(i32),
     MidiPortError(i32),
+    MissingOptionValueError(String),
     NoControllerFound,
     OutputSendError(i32),
+    ProjectParseError(serde_json::Error),
+    ProjectReadError(std::io::Error),
+    ProjectWriteError(std::io::Error),
     SourceNotFoundError,
     SourceListenError(i32),
     SourceUniqueIdError,
+    UnknownOptionError(String),
 }
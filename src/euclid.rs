@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+
+/**
+ * A Euclidean rhythm spreads its hits as evenly as they will go over its
+ * steps, then turns the whole thing later by some steps. Three hits over eight
+ * steps is the tresillo.
+ */
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Euclid {
+    pub hits: usize,
+    pub steps: usize,
+    pub rotation: usize,
+}
+
+impl Default for Euclid {
+    fn default() -> Self {
+        Euclid {
+            hits: 3,
+            steps: 8,
+            rotation: 0,
+        }
+    }
+}
+
+impl Euclid {
+    // Whether the rhythm has a hit on a step, repeating past its last step.
+    pub fn hit(&self, step: usize) -> bool {
+        let steps = self.steps.max(1);
+        let hits = self.hits.min(steps);
+        let turned = (step % steps + steps - self.rotation % steps) % steps;
+        turned * hits % steps < hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hits(euclid: Euclid) -> Vec<bool> {
+        (0..euclid.steps).map(|step| euclid.hit(step)).collect()
+    }
+
+    #[test]
+    fn three_over_eight_is_the_tresillo() {
+        let tresillo = hits(Euclid::default());
+        assert_eq!(
+            tresillo,
+            vec![true, false, false, true, false, false, true, false]
+        );
+    }
+
+    #[test]
+    fn turning_moves_every_hit_later() {
+        let euclid = Euclid::default();
+        let turned = Euclid {
+            rotation: 2,
+            ..euclid
+        };
+        assert!((0..16).all(|step| turned.hit(step + 2) == euclid.hit(step)));
+        assert!(turned.hit(2) && !turned.hit(3));
+    }
+
+    #[test]
+    fn there_are_never_more_hits_than_steps() {
+        let euclid = Euclid {
+            hits: 9,
+            steps: 4,
+            rotation: 0,
+        };
+        assert!(hits(euclid).into_iter().all(|hit| hit));
+        let empty = Euclid { hits: 0, ..euclid };
+        assert!(hits(empty).into_iter().all(|hit| !hit));
+    }
+}
@@ -0,0 +1,167 @@
+use crate::state::{Layer, LayerKind, Note, Section, Settings};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/**
+ * What a follower layer plays, such as layer 0 a third above, two steps
+ * later. Degrees are of the scale of the project.
+ */
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Follow {
+    pub layer: usize,
+    pub degrees: i32,
+    pub delay: usize,
+}
+
+/**
+ * Followers play the notes of the layer they follow rather than any of their
+ * own, so they keep up with whatever is done to it. Their steps are filled in
+ * again here whenever the section may have changed, so they are played and
+ * shown like any other layer's. Everything else about a follower, such as its
+ * rate, is its own.
+ */
+pub fn follow_layers(settings: &Settings, section: &mut Section) {
+    let followed = section
+        .layers
+        .iter()
+        .map(|layer| match layer.kind {
+            LayerKind::Follower => Some(
+                followed_notes(settings, section, layer, section.layers.len())
+                    .into_owned(),
+            ),
+            LayerKind::Melodic
+            | LayerKind::Drum { .. }
+            | LayerKind::Arpeggio => None,
+        })
+        .collect::<Vec<Option<Vec<Note>>>>();
+    section
+        .layers
+        .iter_mut()
+        .zip(followed)
+        .for_each(|(layer, notes)| {
+            if let Some(notes) = notes {
+                layer.notes = notes;
+            }
+        });
+}
+
+// Followers can follow followers, but only so far, in case they go around in
+// a circle.
+fn followed_notes<'a>(
+    settings: &Settings,
+    section: &Section,
+    layer: &'a Layer,
+    depth: usize,
+) -> Cow<'a, [Note]> {
+    match layer.kind {
+        LayerKind::Follower => {
+            let source = section
+                .layers
+                .get(layer.follow.layer)
+                .filter(|_| depth > 0)
+                .map(|source| {
+                    let notes =
+                        followed_notes(settings, section, source, depth - 1);
+                    (source, notes)
+                });
+            (0..layer.notes.len())
+                .map(|step| {
+                    source
+                        .as_ref()
+                        .and_then(|(source, notes)| {
+                            followed_note(
+                                settings,
+                                &layer.follow,
+                                source,
+                                notes,
+                                step,
+                            )
+                        })
+                        .unwrap_or_default()
+                })
+                .collect()
+        }
+        LayerKind::Melodic | LayerKind::Drum { .. } | LayerKind::Arpeggio => {
+            Cow::Borrowed(&layer.notes)
+        }
+    }
+}
+
+// The note of the layer followed, some steps back, moved up or down the scale.
+// Drum voices can be followed too, though they can't be moved.
+fn followed_note(
+    settings: &Settings,
+    follow: &Follow,
+    source: &Layer,
+    notes: &[Note],
+    step: usize,
+) -> Option<Note> {
+    let length = source.length.max(1);
+    let pitched = match source.kind {
+        LayerKind::Drum { .. } => false,
+        LayerKind::Melodic | LayerKind::Arpeggio | LayerKind::Follower => true,
+    };
+    notes
+        .get((step % length + length - follow.delay % length) % length)
+        .map(|note| Note {
+            octaves: note
+                .octaves
+                .iter()
+                .filter_map(|row| source.pitch(*row))
+                .filter_map(|pitch| match follow.degrees {
+                    0 => Some(pitch),
+                    _ if !pitched => Some(pitch),
+                    degrees => settings.scale.shift(pitch, degrees),
+                })
+                .map(usize::from)
+                .collect(),
+            ..note.clone()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::initial_state;
+
+    fn section_with_follower(follow: Follow) -> Section {
+        let mut section =
+            initial_state(Settings::default()).sections[0].clone();
+        section.layers[0].notes[0] = Note {
+            octaves: vec![60],
+            length: 1,
+            ..Note::default()
+        };
+        section.layers[1].kind = LayerKind::Follower;
+        section.layers[1].follow = follow;
+        section
+    }
+
+    #[test]
+    fn followers_play_the_notes_they_follow_up_the_scale_and_later() {
+        let mut section = section_with_follower(Follow {
+            layer: 0,
+            degrees: 2,
+            delay: 1,
+        });
+        follow_layers(&Settings::default(), &mut section);
+        let notes = &section.layers[1].notes;
+        assert!(notes[0].octaves.is_empty());
+        assert_eq!(notes[1].octaves, vec![64]);
+    }
+
+    #[test]
+    fn followers_going_around_in_a_circle_play_nothing() {
+        let mut section = section_with_follower(Follow {
+            layer: 2,
+            ..Follow::default()
+        });
+        section.layers[2].kind = LayerKind::Follower;
+        section.layers[2].follow.layer = 1;
+        follow_layers(&Settings::default(), &mut section);
+        assert!(section.layers[1..=2]
+            .iter()
+            .flat_map(|layer| &layer.notes)
+            .all(|note| note.octaves.is_empty()));
+    }
+}
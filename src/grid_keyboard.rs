@@ -0,0 +1,77 @@
+use crate::state::{Layer, LayerKind, Settings};
+
+// Each row starts this many degrees of the scale above the one below, which is
+// a fourth in the seven note scales. The shape of a chord is then the same
+// wherever it is played on the grid.
+const ROW_DEGREES: usize = 3;
+
+/**
+ * The row of the active layer under pad x, y when the grid is played as a
+ * keyboard. Melodic layers are laid out in the scale of the project, from the
+ * root at or below the lowest row the steps view shows, so scrolling the rows
+ * moves the keyboard too. Drum layers get a pad for each voice.
+ */
+pub fn key_row(
+    settings: &Settings,
+    layer: &Layer,
+    row_offset: usize,
+    x: usize,
+    y: usize,
+) -> Option<usize> {
+    match &layer.kind {
+        LayerKind::Melodic | LayerKind::Arpeggio | LayerKind::Follower => {
+            u8::try_from(row_offset)
+                .ok()
+                .and_then(|from| {
+                    settings.scale.degree_pitch(
+                        from.min(127),
+                        (x + y * ROW_DEGREES) as i32,
+                    )
+                })
+                .map(usize::from)
+        }
+        LayerKind::Drum { .. } => {
+            Some(row_offset + x + y * settings.page_length)
+        }
+    }
+    .filter(|row| *row < layer.row_count(settings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{initial_state, DrumVoice};
+
+    #[test]
+    fn rows_of_keys_go_up_a_fourth() {
+        let settings = Settings::default();
+        let layer = &initial_state(settings.clone()).sections[0].layers[0];
+        let key = |x, y| key_row(&settings, layer, 61, x, y);
+        assert_eq!(key(0, 0), Some(60));
+        assert_eq!(key(1, 0), Some(62));
+        assert_eq!(key(0, 1), Some(65));
+        assert_eq!(key(3, 1), Some(71));
+    }
+
+    #[test]
+    fn drum_keys_run_out_with_the_voices() {
+        let settings = Settings::default();
+        let mut layer =
+            initial_state(settings.clone()).sections[0].layers[0].clone();
+        let voice = DrumVoice {
+            name: "Kick".to_string(),
+            pitch: 36,
+            color: 0,
+        };
+        layer.kind = LayerKind::Drum {
+            voices: vec![voice; settings.page_length + 2],
+        };
+        assert_eq!(key_row(&settings, &layer, 0, 0, 0), Some(0));
+        assert_eq!(
+            key_row(&settings, &layer, 0, 1, 1),
+            Some(settings.page_length + 1)
+        );
+        assert_eq!(key_row(&settings, &layer, 0, 2, 1), None);
+        assert_eq!(key_row(&settings, &layer, 1, 1, 1), None);
+    }
+}
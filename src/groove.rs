@@ -0,0 +1,82 @@
+use crate::error::AppError;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+// Grooves lean a step up to half a step either way, as far as a nudge does.
+// The scheduler only looks so far either side of a step, so anything further
+// would go unplayed.
+pub const MAX_GROOVE_TIMING: i32 = 50;
+
+/**
+ * How far one step of a groove leans off the grid. Timing is a percentage of a
+ * step, negative being early, and is held to within MAX_GROOVE_TIMING.
+ * Velocity is added to the velocity of the note.
+ */
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct GrooveStep {
+    #[serde(default)]
+    pub timing: i32,
+    #[serde(default)]
+    pub velocity: i32,
+}
+
+/**
+ * A Groove is a feel lifted from somewhere else, such as a 16 step groove
+ * from an MPC. It is laid over every layer as it plays, step by step, and
+ * repeats if the layer is longer than the groove. The pattern itself is left
+ * alone.
+ *
+ * Grooves are read from JSON like so:
+ *
+ *   {
+ *     "name": "Lazy",
+ *     "steps": [{"timing": 0, "velocity": 10}, {"timing": 12, "velocity": -8}]
+ *   }
+ */
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct Groove {
+    pub name: String,
+    pub steps: Vec<GrooveStep>,
+}
+
+impl Groove {
+    pub fn step(&self, steps_taken: usize) -> Option<&GrooveStep> {
+        match self.steps.len() {
+            0 => None,
+            len => self.steps.get(steps_taken % len),
+        }
+    }
+}
+
+pub fn read_groove(path: &Path) -> Result<Groove, AppError> {
+    let file = File::open(path).map_err(AppError::GrooveReadError)?;
+    serde_json::from_reader(BufReader::new(file))
+        .map_err(AppError::GrooveParseError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grooves_repeat_over_longer_layers() {
+        let groove = serde_json::from_str::<Groove>(
+            r#"{
+                "name": "Lazy",
+                "steps": [{"timing": 0, "velocity": 10}, {"timing": 12}]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(groove.step(2), groove.step(0));
+        assert_eq!(
+            groove.step(3),
+            Some(&GrooveStep {
+                timing: 12,
+                velocity: 0,
+            })
+        );
+        assert_eq!(Groove::default().step(0), None);
+    }
+}
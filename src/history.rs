@@ -0,0 +1,338 @@
+use crate::action::Action;
+use crate::follow::follow_layers;
+use crate::playback::TICKS_PER_STEP;
+use crate::reducer;
+use crate::state::{GlobalState, Layer, Section};
+use std::collections::VecDeque;
+
+// How many undo steps we hang onto before the oldest ones fall off.
+pub const HISTORY_LIMIT: usize = 64;
+
+/**
+ * A layer as it was before an edit, and where it goes back to.
+ */
+#[derive(Clone)]
+pub struct LayerSnapshot {
+    pub section_index: usize,
+    pub layer_index: usize,
+    pub layer: Layer,
+}
+
+/**
+ * History holds snapshots of the layers edits changed so they can be taken
+ * back. Only the pattern is recorded. What is selected or where the playhead
+ * sits is not something anyone wants to undo. Layers the edit didn't touch are
+ * left out, so undoing doesn't also take back whatever has happened to them
+ * since, such as a mutation.
+ */
+#[derive(Clone, Default)]
+pub struct History {
+    pub past: VecDeque<Vec<LayerSnapshot>>,
+    pub future: Vec<Vec<LayerSnapshot>>,
+    // The step of the clock of the last recorded edit. Edits that land within
+    // the same step are undone as a single step, so mashing a handful of pads
+    // doesn't take a handful of undos to take back. We lean on the clock
+    // rather than the wall clock so replaying a journal lands on the same
+    // history.
+    pub last_edit: Option<usize>,
+}
+
+fn records(action: &Action) -> bool {
+    matches!(
+        action,
+        Action::ArpeggioToggle
+            | Action::AutomationLaneChange { .. }
+            | Action::ChordMemorize { .. }
+            | Action::ChordStamp { .. }
+            | Action::Clear(_)
+            | Action::DirectionCycle
+            | Action::Duplicate { .. }
+            | Action::DrumModeToggle
+            | Action::EuclidFill { .. }
+            | Action::GridToggle { .. }
+            | Action::KeyboardNote { .. }
+            | Action::KeyPress { .. }
+            | Action::LayerLengthChange { .. }
+            | Action::LayerRateChange { .. }
+            | Action::LockChange { .. }
+            | Action::MutationFreezeToggle
+            | Action::Paste(_)
+            | Action::StepConditionCycle { .. }
+            | Action::StepNudge { .. }
+            | Action::StepRatchet { .. }
+    )
+}
+
+// The layers that differ after an edit, as they were before it.
+fn changed_layers(
+    before: Vec<Section>,
+    after: &[Section],
+) -> Vec<LayerSnapshot> {
+    before
+        .into_iter()
+        .zip(after)
+        .enumerate()
+        .flat_map(|(section_index, (before, after))| {
+            before
+                .layers
+                .into_iter()
+                .zip(&after.layers)
+                .enumerate()
+                .filter(|(_, (before, after))| before != *after)
+                .map(|(layer_index, (layer, _))| LayerSnapshot {
+                    section_index,
+                    layer_index,
+                    layer,
+                })
+                .collect::<Vec<LayerSnapshot>>()
+        })
+        .collect()
+}
+
+fn record(history: &mut History, changed: Vec<LayerSnapshot>, step: usize) {
+    match history.past.back_mut() {
+        // A layer already changed within the step goes back to how it was
+        // before the first of the edits.
+        Some(last) if history.last_edit == Some(step) => {
+            changed.into_iter().for_each(|snapshot| {
+                let kept = last.iter().any(|x| {
+                    x.section_index == snapshot.section_index
+                        && x.layer_index == snapshot.layer_index
+                });
+                if !kept {
+                    last.push(snapshot);
+                }
+            })
+        }
+        _ => {
+            history.past.push_back(changed);
+            if history.past.len() > HISTORY_LIMIT {
+                history.past.pop_front();
+            }
+        }
+    }
+    history.future.clear();
+    history.last_edit = Some(step);
+}
+
+// Put each layer back as it was, handing back the layers put aside so the
+// edit can be made again. Followers then follow whatever is there now.
+fn restore(
+    state: &mut GlobalState,
+    snapshots: Vec<LayerSnapshot>,
+) -> Vec<LayerSnapshot> {
+    let replaced = snapshots
+        .into_iter()
+        .filter_map(|snapshot| {
+            state
+                .sections
+                .get_mut(snapshot.section_index)
+                .and_then(|section| {
+                    section.layers.get_mut(snapshot.layer_index)
+                })
+                .map(|layer| LayerSnapshot {
+                    section_index: snapshot.section_index,
+                    layer_index: snapshot.layer_index,
+                    layer: std::mem::replace(layer, snapshot.layer),
+                })
+        })
+        .collect();
+    for section in state.sections.iter_mut() {
+        follow_layers(&state.settings, section);
+    }
+    replaced
+}
+
+/**
+ * What the store holds: the state, with the history of its sections kept
+ * alongside rather than inside it. The reducer clones the state it is handed,
+ * and the history can run to dozens of copies of every section, so it stays
+ * out here where it is only ever moved.
+ */
+#[derive(Clone, Default)]
+pub struct Undoable {
+    pub state: GlobalState,
+    pub history: History,
+}
+
+impl Undoable {
+    pub fn new(state: GlobalState) -> Undoable {
+        Undoable {
+            state,
+            history: History::default(),
+        }
+    }
+}
+
+fn undo(undoable: Undoable) -> Undoable {
+    let Undoable {
+        mut state,
+        mut history,
+    } = undoable;
+    if let Some(snapshots) = history.past.pop_back() {
+        let replaced = restore(&mut state, snapshots);
+        history.future.push(replaced);
+        history.last_edit = None;
+    }
+    Undoable { state, history }
+}
+
+fn redo(undoable: Undoable) -> Undoable {
+    let Undoable {
+        mut state,
+        mut history,
+    } = undoable;
+    if let Some(snapshots) = history.future.pop() {
+        let replaced = restore(&mut state, snapshots);
+        history.past.push_back(replaced);
+        history.last_edit = None;
+    }
+    Undoable { state, history }
+}
+
+/**
+ * Wraps reducer::reducer, taking a snapshot of the layers an edit changes so
+ * they can be restored with Undo.
+ */
+pub fn reducer(undoable: Undoable, action: Action) -> Undoable {
+    match action {
+        Action::Undo => undo(undoable),
+        Action::Redo => redo(undoable),
+        _ if records(&action) => {
+            let Undoable { state, mut history } = undoable;
+            let before = state.sections.clone();
+            let state = reducer::reducer(state, action);
+            let changed = changed_layers(before, &state.sections);
+            if !changed.is_empty() {
+                let step = state.player.clock / TICKS_PER_STEP;
+                record(&mut history, changed, step);
+            }
+            Undoable { state, history }
+        }
+        _ => Undoable {
+            state: reducer::reducer(undoable.state, action),
+            history: undoable.history,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{initial_state, Settings};
+
+    fn dispatch(undoable: Undoable, actions: Vec<Action>) -> Undoable {
+        actions.into_iter().fold(undoable, reducer)
+    }
+
+    fn start() -> Undoable {
+        Undoable::new(initial_state(Settings::default()))
+    }
+
+    fn tick_a_step(undoable: Undoable) -> Undoable {
+        (0..TICKS_PER_STEP).fold(undoable, |undoable, _| {
+            reducer(undoable, Action::TimeInterval)
+        })
+    }
+
+    #[test]
+    fn undo_and_redo_move_between_edits() {
+        let before = start();
+        let edited =
+            dispatch(before.clone(), vec![Action::GridToggle { x: 0, y: 0 }]);
+        assert!(edited.state.sections != before.state.sections);
+        let undone = dispatch(edited.clone(), vec![Action::Undo]);
+        assert!(undone.state.sections == before.state.sections);
+        let redone = dispatch(undone, vec![Action::Redo]);
+        assert!(redone.state.sections == edited.state.sections);
+    }
+
+    #[test]
+    fn edits_in_the_same_step_are_undone_together() {
+        let undoable = dispatch(
+            start(),
+            vec![
+                Action::GridToggle { x: 0, y: 0 },
+                Action::GridToggle { x: 1, y: 0 },
+            ],
+        );
+        assert_eq!(undoable.history.past.len(), 1);
+        let undoable = tick_a_step(undoable);
+        let undoable =
+            dispatch(undoable, vec![Action::GridToggle { x: 2, y: 0 }]);
+        assert_eq!(undoable.history.past.len(), 2);
+    }
+
+    #[test]
+    fn only_edits_are_recorded() {
+        let undoable = dispatch(
+            start(),
+            vec![Action::LayerSelect { pos: 1 }, Action::TimeInterval],
+        );
+        assert!(undoable.history.past.is_empty());
+    }
+
+    #[test]
+    fn a_new_edit_drops_what_could_be_redone() {
+        let undoable = dispatch(
+            start(),
+            vec![Action::GridToggle { x: 0, y: 0 }, Action::Undo],
+        );
+        assert_eq!(undoable.history.future.len(), 1);
+        let undoable =
+            dispatch(undoable, vec![Action::GridToggle { x: 1, y: 0 }]);
+        assert!(undoable.history.future.is_empty());
+    }
+
+    #[test]
+    fn history_stops_at_the_limit() {
+        let undoable = (0..HISTORY_LIMIT + 4).fold(start(), |undoable, i| {
+            let x = (i % 8) as u32;
+            tick_a_step(dispatch(
+                undoable,
+                vec![Action::GridToggle { x, y: 0 }],
+            ))
+        });
+        assert_eq!(undoable.history.past.len(), HISTORY_LIMIT);
+    }
+
+    #[test]
+    fn undoing_leaves_the_rows_where_they_were_scrolled() {
+        let undoable = dispatch(
+            start(),
+            vec![
+                Action::GridToggle { x: 0, y: 0 },
+                Action::RowScroll { rows: 3 },
+                Action::Undo,
+            ],
+        );
+        let state = &undoable.state;
+        let layer = &state.sections[0].layers[0];
+        let scrolled = state.player.row_offset(&state.settings, 0, 0, layer);
+        assert_eq!(scrolled, layer.default_row_offset(&state.settings) + 3);
+    }
+
+    #[test]
+    fn choosing_the_automation_lane_is_recorded() {
+        let undoable =
+            dispatch(start(), vec![Action::AutomationLaneChange { cc: 7 }]);
+        assert_eq!(undoable.history.past.len(), 1);
+        let undone = dispatch(undoable, vec![Action::Undo]);
+        assert_eq!(
+            undone.state.sections[0].layers[0].automation_cc,
+            start().state.sections[0].layers[0].automation_cc,
+        );
+    }
+
+    #[test]
+    fn undoing_leaves_layers_the_edit_did_not_touch() {
+        let mut undoable =
+            dispatch(start(), vec![Action::GridToggle { x: 0, y: 0 }]);
+        // The second layer changes without an edit, as when it mutates.
+        undoable.state.sections[0].layers[1].notes[3].length = 1;
+        let undone = dispatch(undoable, vec![Action::Undo]);
+        let layers = &undone.state.sections[0].layers;
+        assert!(layers[0] == start().state.sections[0].layers[0]);
+        assert_eq!(layers[1].notes[3].length, 1);
+    }
+}
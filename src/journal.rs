@@ -0,0 +1,294 @@
+use crate::action::Action;
+use crate::error::AppError;
+use crate::history::{self, Undoable};
+use crate::project::Project;
+use crate::state::{initial_state, GlobalState, Settings};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::Instant;
+
+/**
+ * An Entry is a single action in the journal: the action, how long after the
+ * journal was opened it was dispatched, and how many ticks of the clock went by
+ * since the entry before. Ticks are counted rather than written out one to a
+ * line, since there are dozens of them a second.
+ */
+#[derive(Deserialize)]
+pub struct Entry {
+    pub elapsed_ms: u64,
+    pub ticks: usize,
+    pub action: Action,
+}
+
+// Same as Entry, but borrows the action so we can write it out before handing
+// it off to the store.
+#[derive(Serialize)]
+struct EntryRef<'a> {
+    elapsed_ms: u64,
+    ticks: usize,
+    action: &'a Action,
+}
+
+/**
+ * Each line of the journal is either the start of a session, with the project
+ * it started from, or an entry dispatched during it.
+ */
+#[derive(Deserialize)]
+enum Line {
+    Session(Project),
+    Entry(Entry),
+}
+
+#[derive(Serialize)]
+enum LineRef<'a> {
+    Session(&'a Project),
+    Entry(EntryRef<'a>),
+}
+
+/**
+ * Everything journaled from starting up until switching off.
+ */
+pub struct Session {
+    pub start: Project,
+    pub entries: Vec<Entry>,
+}
+
+/**
+ * The Journal is an append-only log of every action dispatched to the store.
+ * Since all state is built by folding actions through the reducer, the
+ * journal is enough to rebuild the state of a session exactly - handy for
+ * reproducing a bug seen on the device or keeping a performance around.
+ *
+ * Each line is written as JSON. Sessions pile up one after another in the
+ * same file, each starting from the project as it was when it started.
+ */
+pub struct Journal {
+    file: File,
+    started: Instant,
+    // Ticks of the clock since the last entry.
+    ticks: usize,
+}
+
+fn write_line(file: &mut File, line: &LineRef) -> Result<(), AppError> {
+    serde_json::to_string(line)
+        .map_err(AppError::JournalParseError)
+        .and_then(|line| {
+            writeln!(file, "{}", line).map_err(AppError::JournalWriteError)
+        })
+}
+
+impl Journal {
+    pub fn open(path: &Path, state: &GlobalState) -> Result<Journal, AppError> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(AppError::JournalWriteError)?;
+        write_line(&mut file, &LineRef::Session(&Project::of(state)))?;
+        Ok(Journal {
+            file,
+            started: Instant::now(),
+            ticks: 0,
+        })
+    }
+
+    pub fn tick(&mut self) {
+        self.ticks += 1;
+    }
+
+    pub fn append(&mut self, action: &Action) -> Result<(), AppError> {
+        let entry = EntryRef {
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+            ticks: std::mem::take(&mut self.ticks),
+            action,
+        };
+        write_line(&mut self.file, &LineRef::Entry(entry))
+    }
+
+    // The ticks since the last entry are written out with nothing after them,
+    // so a replay ends on the same tick the session did.
+    pub fn close(&mut self) -> Result<(), AppError> {
+        match self.ticks {
+            0 => Ok(()),
+            _ => self.append(&Action::Noop),
+        }
+    }
+}
+
+/**
+ * The last session in the journal.
+ */
+pub fn read_journal(path: &Path) -> Result<Session, AppError> {
+    let file = File::open(path).map_err(AppError::JournalReadError)?;
+    BufReader::new(file)
+        .lines()
+        .filter(|line| line.as_ref().map_or(true, |x| !x.trim().is_empty()))
+        .try_fold(None, |session: Option<Session>, line| {
+            let line =
+                line.map_err(AppError::JournalReadError).and_then(|x| {
+                    serde_json::from_str(&x)
+                        .map_err(AppError::JournalParseError)
+                })?;
+            match (session, line) {
+                (_, Line::Session(start)) => Ok(Some(Session {
+                    start,
+                    entries: vec![],
+                })),
+                (Some(mut session), Line::Entry(entry)) => {
+                    session.entries.push(entry);
+                    Ok(Some(session))
+                }
+                (None, Line::Entry(_)) => Err(AppError::JournalSessionError),
+            }
+        })
+        .and_then(|session| session.ok_or(AppError::JournalSessionError))
+}
+
+/**
+ * Rebuild state by running every entry of a session through the reducer, in
+ * order, from the project the session started from, with the ticks of the
+ * clock that went by in between. The time of the entries is not used here.
+ * The reducer only goes by the ticks, so the replay does too.
+ */
+pub fn replay(settings: Settings, session: Session) -> Undoable {
+    let start = Undoable::new(session.start.open(initial_state(settings)));
+    session.entries.into_iter().fold(start, |undoable, entry| {
+        let ticked = (0..entry.ticks).fold(undoable, |undoable, _| {
+            history::reducer(undoable, Action::TimeInterval)
+        });
+        history::reducer(ticked, entry.action)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::PlayMode;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn journal_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "grinstrument-{}-{}.jsonl",
+            name,
+            std::process::id()
+        ))
+    }
+
+    fn tick(
+        undoable: Undoable,
+        journal: &mut Journal,
+        ticks: usize,
+    ) -> Undoable {
+        (0..ticks).fold(undoable, |undoable, _| {
+            journal.tick();
+            history::reducer(undoable, Action::TimeInterval)
+        })
+    }
+
+    // Run a session from a state, journaling it as it goes: each action after
+    // some ticks, then some ticks more before switching off.
+    fn journaled(
+        path: &Path,
+        state: GlobalState,
+        actions: Vec<(usize, Action)>,
+        after: usize,
+    ) -> Undoable {
+        let mut journal = Journal::open(path, &state).unwrap();
+        let undoable = actions.into_iter().fold(
+            Undoable::new(state),
+            |undoable, (ticks, action)| {
+                let ticked = tick(undoable, &mut journal, ticks);
+                journal.append(&action).unwrap();
+                history::reducer(ticked, action)
+            },
+        );
+        let undoable = tick(undoable, &mut journal, after);
+        journal.close().unwrap();
+        undoable
+    }
+
+    #[test]
+    fn replays_land_where_the_session_did() {
+        let path = journal_path("replays-land");
+        let session = journaled(
+            &path,
+            initial_state(Settings::default()),
+            vec![
+                (0, Action::GridToggle { x: 0, y: 0 }),
+                (5, Action::PlayModeChange(PlayMode::Playing)),
+                (30, Action::GridToggle { x: 3, y: 1 }),
+                (2, Action::Undo),
+            ],
+            7,
+        );
+        let replayed =
+            replay(Settings::default(), read_journal(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+        assert!(replayed.state.sections == session.state.sections);
+        assert_eq!(replayed.state.player.clock, session.state.player.clock);
+        assert_eq!(
+            replayed.state.player.interval,
+            session.state.player.interval
+        );
+        assert_eq!(replayed.history.past.len(), session.history.past.len());
+    }
+
+    #[test]
+    fn only_the_last_session_is_replayed() {
+        let path = journal_path("last-session");
+        journaled(
+            &path,
+            initial_state(Settings::default()),
+            vec![(0, Action::GridToggle { x: 0, y: 0 })],
+            3,
+        );
+        let second = journaled(
+            &path,
+            initial_state(Settings::default()),
+            vec![(1, Action::GridToggle { x: 1, y: 0 })],
+            0,
+        );
+        let replayed =
+            replay(Settings::default(), read_journal(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+        assert!(replayed.state.sections == second.state.sections);
+        assert_eq!(replayed.state.player.clock, second.state.player.clock);
+    }
+
+    // A session started from a project, which was saved again with its edits
+    // in by the time it is replayed. Replaying goes from the project as it was
+    // when the session started, rather than putting the edits in twice.
+    #[test]
+    fn replays_start_from_the_project_the_session_did() {
+        let path = journal_path("project-session");
+        let mut opened = initial_state(Settings::default());
+        opened.sections[0].layers[0].notes[4].octaves = vec![64];
+        opened.sections[0].layers[0].notes[4].length = 1;
+        let session = journaled(
+            &path,
+            opened,
+            vec![(0, Action::GridToggle { x: 2, y: 0 })],
+            0,
+        );
+        let replayed =
+            replay(Settings::default(), read_journal(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+        assert!(replayed.state.sections == session.state.sections);
+    }
+
+    #[test]
+    fn entries_come_after_the_start_of_a_session() {
+        let path = journal_path("no-session");
+        fs::write(
+            &path,
+            r#"{"Entry":{"elapsed_ms":5,"ticks":0,"action":"TimeInterval"}}"#,
+        )
+        .unwrap();
+        let read = read_journal(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(read, Err(AppError::JournalSessionError)));
+    }
+}
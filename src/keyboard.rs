@@ -0,0 +1,43 @@
+use crate::action::Action;
+
+// Universal MIDI packets carrying MIDI 1.0 channel voice messages.
+const MIDI_1_MESSAGE_TYPE: u32 = 0x2;
+const NOTE_ON_STATUS: u32 = 0x9;
+const PITCH_MASK: u32 = 0x00007f00;
+const VELOCITY_MASK: u32 = 0x0000007f;
+
+/**
+ * Turns what comes in from a MIDI keyboard into actions. Only the keys being
+ * pressed matter. What is done with them is down to what the player is up to.
+ */
+pub fn keyboard_to_action(packet: u32) -> Action {
+    let message_type = packet >> 28;
+    let status = (packet >> 20) & 0xf;
+    // A note on with no velocity is a note off in disguise.
+    if message_type == MIDI_1_MESSAGE_TYPE
+        && status == NOTE_ON_STATUS
+        && packet & VELOCITY_MASK > 0
+    {
+        Action::KeyboardNote {
+            pitch: ((packet & PITCH_MASK) >> 8) as u8,
+        }
+    } else {
+        Action::Noop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_keys_pressed_come_through() {
+        assert!(matches!(
+            keyboard_to_action(0x20933c64),
+            Action::KeyboardNote { pitch: 60 }
+        ));
+        assert!(matches!(keyboard_to_action(0x20903c00), Action::Noop));
+        assert!(matches!(keyboard_to_action(0x20803c40), Action::Noop));
+        assert!(matches!(keyboard_to_action(0x10f80000), Action::Noop));
+    }
+}
@@ -0,0 +1,279 @@
+use crate::chord::CHORDS;
+use crate::state::{
+    ArpeggioMode, ClockRate, Direction, Layer, LayerKind, Settings, View,
+};
+
+// Clock rates a layer can be picked to run at, as (multiplier, divider).
+// Slowest first, the same as from the track buttons.
+pub const CLOCK_RATES: [(usize, usize); 8] = [
+    (1, 4),
+    (1, 3),
+    (1, 2),
+    (1, 1),
+    (3, 2),
+    (2, 1),
+    (3, 1),
+    (4, 1),
+];
+
+pub const DIRECTIONS: [Direction; 5] = [
+    Direction::Forward,
+    Direction::Reverse,
+    Direction::PingPong,
+    Direction::Random,
+    Direction::Brownian,
+];
+
+// Swing a layer can have of its own, the usual amounts found on an MPC. A layer
+// without any follows the project.
+pub const SWING_AMOUNTS: [usize; 7] = [50, 54, 58, 62, 66, 71, 75];
+
+// How long a chord can take to strum, as a percentage of a step.
+pub const STRUM_AMOUNTS: [usize; 8] = [0, 5, 10, 15, 20, 30, 40, 50];
+
+pub const ARPEGGIO_MODES: [ArpeggioMode; 5] = [
+    ArpeggioMode::Up,
+    ArpeggioMode::Down,
+    ArpeggioMode::UpDown,
+    ArpeggioMode::Random,
+    ArpeggioMode::AsPlayed,
+];
+
+// Arpeggios run faster than layers usually do, so their rates go higher. Each
+// of them divides a step of the clock evenly.
+pub const ARPEGGIO_RATES: [(usize, usize); 8] = [
+    (1, 2),
+    (1, 1),
+    (3, 2),
+    (2, 1),
+    (3, 1),
+    (4, 1),
+    (6, 1),
+    (12, 1),
+];
+
+pub const MAX_ARPEGGIO_OCTAVES: usize = 4;
+
+// Degrees of the scale a follower can play above or below the layer it
+// follows. An octave, a fifth, a third, and in between, in either direction.
+pub const FOLLOW_DEGREES: [i32; 8] = [-7, -4, -2, 0, 2, 4, 5, 7];
+
+pub const MAX_FOLLOW_DELAY: usize = 8;
+
+/**
+ * The settings of a layer that can be changed from the layer settings view.
+ * Each one gets a row of the grid, starting from the top, with a pad for each
+ * choice it has.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LayerSetting {
+    Rate,
+    Direction,
+    Swing,
+    Strum,
+    Chord,
+    ArpeggioMode,
+    ArpeggioRate,
+    ArpeggioOctaves,
+    // Each layer of the section. Picking the layer followed again stops
+    // following it.
+    FollowLayer,
+    FollowDegrees,
+    FollowDelay,
+}
+
+const LAYER_SETTINGS: [LayerSetting; 8] = [
+    LayerSetting::Rate,
+    LayerSetting::Direction,
+    LayerSetting::Swing,
+    LayerSetting::Strum,
+    LayerSetting::Chord,
+    LayerSetting::ArpeggioMode,
+    LayerSetting::ArpeggioRate,
+    LayerSetting::ArpeggioOctaves,
+];
+
+const FOLLOW_SETTINGS: [LayerSetting; 3] = [
+    LayerSetting::FollowLayer,
+    LayerSetting::FollowDegrees,
+    LayerSetting::FollowDelay,
+];
+
+impl LayerSetting {
+    // The setting on a row of the grid in a view, if any.
+    pub fn from_row(
+        settings: &Settings,
+        view: &View,
+        y: usize,
+    ) -> Option<LayerSetting> {
+        let rows: &[LayerSetting] = match view {
+            View::Follow => &FOLLOW_SETTINGS,
+            View::Steps
+            | View::PageOverview
+            | View::LayerSettings
+            | View::Automation
+            | View::Keyboard
+            | View::Euclid
+            | View::Mutation
+            | View::Morph => &LAYER_SETTINGS,
+        };
+        settings
+            .visible_rows
+            .checked_sub(y + 1)
+            .and_then(|row| rows.get(row))
+            .copied()
+    }
+
+    pub fn choice_count(&self, settings: &Settings) -> usize {
+        match self {
+            LayerSetting::Rate => CLOCK_RATES.len(),
+            LayerSetting::Direction => DIRECTIONS.len(),
+            // Following the project comes first.
+            LayerSetting::Swing => SWING_AMOUNTS.len() + 1,
+            LayerSetting::Strum => STRUM_AMOUNTS.len(),
+            LayerSetting::Chord => CHORDS.len(),
+            LayerSetting::ArpeggioMode => ARPEGGIO_MODES.len(),
+            LayerSetting::ArpeggioRate => ARPEGGIO_RATES.len(),
+            LayerSetting::ArpeggioOctaves => MAX_ARPEGGIO_OCTAVES,
+            LayerSetting::FollowLayer => settings.layer_count,
+            LayerSetting::FollowDegrees => FOLLOW_DEGREES.len(),
+            LayerSetting::FollowDelay => MAX_FOLLOW_DELAY,
+        }
+    }
+
+    // Which of the choices the layer has now, if it is one of them.
+    pub fn chosen(&self, layer: &Layer) -> Option<usize> {
+        match self {
+            LayerSetting::Rate => CLOCK_RATES.iter().position(|(m, d)| {
+                layer.rate
+                    == ClockRate {
+                        multiplier: *m,
+                        divider: *d,
+                    }
+            }),
+            LayerSetting::Direction => {
+                DIRECTIONS.iter().position(|x| *x == layer.direction)
+            }
+            LayerSetting::Swing => match layer.swing {
+                None => Some(0),
+                Some(swing) => SWING_AMOUNTS
+                    .iter()
+                    .position(|x| *x == swing)
+                    .map(|x| x + 1),
+            },
+            LayerSetting::Strum => {
+                STRUM_AMOUNTS.iter().position(|x| *x == layer.strum)
+            }
+            LayerSetting::Chord => {
+                CHORDS.iter().position(|x| *x == layer.chord)
+            }
+            LayerSetting::ArpeggioMode => ARPEGGIO_MODES
+                .iter()
+                .position(|x| *x == layer.arpeggio.mode),
+            LayerSetting::ArpeggioRate => {
+                ARPEGGIO_RATES.iter().position(|(m, d)| {
+                    layer.arpeggio.rate
+                        == ClockRate {
+                            multiplier: *m,
+                            divider: *d,
+                        }
+                })
+            }
+            // One octave comes first.
+            LayerSetting::ArpeggioOctaves => {
+                layer.arpeggio.octaves.checked_sub(1)
+            }
+            // Only followers follow anything.
+            LayerSetting::FollowLayer => match layer.kind {
+                LayerKind::Follower => Some(layer.follow.layer),
+                LayerKind::Melodic
+                | LayerKind::Drum { .. }
+                | LayerKind::Arpeggio => None,
+            },
+            LayerSetting::FollowDegrees => FOLLOW_DEGREES
+                .iter()
+                .position(|x| *x == layer.follow.degrees),
+            LayerSetting::FollowDelay => Some(layer.follow.delay),
+        }
+    }
+
+    pub fn choose(&self, layer: &mut Layer, choice: usize) -> Option<()> {
+        match self {
+            LayerSetting::Rate => CLOCK_RATES.get(choice).map(|(m, d)| {
+                layer.rate = ClockRate {
+                    multiplier: *m,
+                    divider: *d,
+                }
+            }),
+            LayerSetting::Direction => {
+                DIRECTIONS.get(choice).map(|x| layer.direction = *x)
+            }
+            LayerSetting::Swing => match choice {
+                0 => {
+                    layer.swing = None;
+                    Some(())
+                }
+                _ => SWING_AMOUNTS
+                    .get(choice - 1)
+                    .map(|x| layer.swing = Some(*x)),
+            },
+            LayerSetting::Strum => {
+                STRUM_AMOUNTS.get(choice).map(|x| layer.strum = *x)
+            }
+            LayerSetting::Chord => CHORDS.get(choice).map(|x| layer.chord = *x),
+            LayerSetting::ArpeggioMode => {
+                ARPEGGIO_MODES.get(choice).map(|x| layer.arpeggio.mode = *x)
+            }
+            LayerSetting::ArpeggioRate => {
+                ARPEGGIO_RATES.get(choice).map(|(m, d)| {
+                    layer.arpeggio.rate = ClockRate {
+                        multiplier: *m,
+                        divider: *d,
+                    }
+                })
+            }
+            LayerSetting::ArpeggioOctaves => (choice < MAX_ARPEGGIO_OCTAVES)
+                .then(|| layer.arpeggio.octaves = choice + 1),
+            // A follower has rows like a melodic layer's, so that is what it
+            // goes back to. Drum layers would lose their voices, and arpeggios
+            // would be played as chords.
+            LayerSetting::FollowLayer => match layer.kind {
+                LayerKind::Drum { .. } | LayerKind::Arpeggio => None,
+                LayerKind::Follower if layer.follow.layer == choice => {
+                    layer.kind = LayerKind::Melodic;
+                    Some(())
+                }
+                LayerKind::Melodic | LayerKind::Follower => {
+                    layer.kind = LayerKind::Follower;
+                    layer.follow.layer = choice;
+                    Some(())
+                }
+            },
+            LayerSetting::FollowDegrees => FOLLOW_DEGREES
+                .get(choice)
+                .map(|x| layer.follow.degrees = *x),
+            LayerSetting::FollowDelay => {
+                (choice < MAX_FOLLOW_DELAY).then(|| layer.follow.delay = choice)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::initial_state;
+
+    #[test]
+    fn only_melodic_layers_become_followers() {
+        let mut layer =
+            initial_state(Settings::default()).sections[0].layers[0].clone();
+        assert_eq!(LayerSetting::FollowLayer.choose(&mut layer, 1), Some(()));
+        assert_eq!(layer.kind, LayerKind::Follower);
+        assert_eq!(LayerSetting::FollowLayer.choose(&mut layer, 1), Some(()));
+        assert_eq!(layer.kind, LayerKind::Melodic);
+        layer.kind = LayerKind::Arpeggio;
+        assert_eq!(LayerSetting::FollowLayer.choose(&mut layer, 1), None);
+        assert_eq!(layer.kind, LayerKind::Arpeggio);
+    }
+}
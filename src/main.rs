@@ -1,54 +1,152 @@
 mod action;
 mod akai_apc_mini_mk2;
+mod capture;
+mod chord;
 mod device;
+mod drum_map;
+mod effects;
 mod error;
+mod euclid;
+mod follow;
+mod grid_keyboard;
+mod groove;
+mod history;
+mod journal;
+mod keyboard;
+mod layer_settings;
 mod midi;
+mod morph;
+mod mutation;
+mod options;
+mod playback;
+mod project;
 mod reducer;
+mod render;
+mod scale;
+mod scheduler;
 mod state;
+mod tempo;
 mod utils;
 
 use crate::akai_apc_mini_mk2::AkaiApcMiniMk2;
 use crate::{
-    action::Action, device::Color, device::ColorStyle, error::AppError,
-    midi::diagnose_midi_devices, state::initial_state,
+    action::Action,
+    error::AppError,
+    midi::diagnose_midi_devices,
+    state::{initial_state, GlobalState},
 };
-use coremidi::{
-    Client, Destination, Destinations, EventList, OutputPort, Protocol, Source,
+use capture::{
+    capture_event, check_capture, read_capture, Capture, CaptureEvent,
+    CaptureOutput,
 };
-use device::Device;
+use coremidi::{Client, Destinations, EventList, Protocol, Source};
+use device::{follow_view, ChangedOutput, Device, MidiOutput};
+use drum_map::read_drum_map;
 use futures::executor::block_on;
-use midi::{connect_to_controller, get_destination, get_source};
+use groove::read_groove;
+use history::Undoable;
+use journal::{read_journal, replay, Journal};
+use keyboard::keyboard_to_action;
+use midi::{
+    connect_to_controller, get_destination, get_source, listen_to_source,
+    PortOutput,
+};
+use options::parse_options;
+use project::{read_project, ProjectFile};
 use redux_rs::Store;
-use state::{GlobalState, Layer, Note, PlayMode, Section};
+use render::state_to_device;
+use scheduler::{Instruments, Scheduler};
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::result::Result;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::{result::Result, time::Duration};
+use std::time::Instant;
+use tempo::Tempo;
 
 include!(concat!(env!("OUT_DIR"), "/constants.rs"));
 
 #[tokio::main]
 async fn main() -> Result<(), AppError> {
+    let mut options = parse_options(env::args().skip(1))?;
+    let device = AkaiApcMiniMk2::default();
+    options.settings.page_length = device.grid_width();
+    options.settings.visible_rows = device.grid_height();
+    if let Some(path) = &options.drum_map {
+        options.settings.drum_voices = read_drum_map(path)?;
+    }
+    if let Some(path) = &options.groove {
+        options.settings.groove = Some(read_groove(path)?);
+    }
+    if let Some(path) = &options.check_capture {
+        return check_capture(&device, options.settings, read_capture(path)?);
+    }
     diagnose_midi_devices();
+    let opened = match &options.project {
+        Some(path) if path.exists() => {
+            println!("Opening {}...", path.display());
+            read_project(path)?.open(initial_state(options.settings.clone()))
+        }
+        _ => initial_state(options.settings.clone()),
+    };
+    let project = options
+        .project
+        .as_ref()
+        .map(|path| Mutex::new(ProjectFile::new(path, &opened)));
+    // A replay starts from wherever the session it replays started, whatever
+    // project is open. The project has its edits in already.
+    let starting_state = match &options.replay {
+        Some(path) => {
+            let session = read_journal(path)?;
+            println!(
+                "Replaying {} actions spanning {}ms from {}...",
+                session.entries.len(),
+                session.entries.last().map_or(0, |entry| entry.elapsed_ms),
+                path.display(),
+            );
+            replay(options.settings.clone(), session)
+        }
+        None => Undoable::new(opened),
+    };
+    let journal = match &options.journal {
+        Some(path) => Some(Arc::new(Mutex::new(Journal::open(
+            path,
+            &starting_state.state,
+        )?))),
+        None => None,
+    };
+    let capture = match &options.capture {
+        Some(path) => Some(Arc::new(Mutex::new(Capture::create(path)?))),
+        None => None,
+    };
+    follow_view(&device, &starting_state.state.player.view);
     let store_mutex = Arc::new(Mutex::new(Store::new_with_state(
-        reducer::reducer,
-        initial_state(),
+        history::reducer,
+        starting_state.clone(),
     )));
-    let device = AkaiApcMiniMk2 {};
+    // Anything timed by hand on the controller is timed from here.
+    let started = Instant::now();
     let callback = enclose!(
-    (store_mutex) move |event_list: &EventList, mut_context: &mut u32| {
+    (store_mutex, device, journal, capture) move |event_list: &EventList, mut_context: &mut u32| {
         println!("Got midi event");
         let context = mut_context.clone();
         if let Ok(store) = store_mutex.lock() {
             for (_size, event_packet) in event_list.iter().enumerate() {
                 for data in event_packet.data() {
-                    block_on(store.dispatch(
-                        device.midi_to_action(context, *data)
-                    ))
+                    capture_event(&capture, CaptureEvent::In(*data));
+                    let ms = started.elapsed().as_millis() as u64;
+                    let action = device.midi_to_action(context, *data, ms);
+                    journal_action(&journal, &action);
+                    block_on(store.dispatch(action));
+                    let view = block_on(store.select(|undoable: &Undoable| {
+                        undoable.state.player.view.clone()
+                    }));
+                    follow_view(&device, &view);
                 }
             }
         }
     });
-    let (_client, mut input_port, output_port) =
+    let (client, mut input_port, output_port) =
         connect_to_controller(callback)?;
     let dest = get_destination("APC mini mk2 Control")
         .ok_or(AppError::DestinationNotFoundError)?;
@@ -61,274 +159,166 @@ async fn main() -> Result<(), AppError> {
     input_port
         .connect_source(&source, source_id)
         .map_err(AppError::SourceListenError)?;
+    // Kept around for as long as we're listening to the keyboard.
+    let _keyboard_port = match &options.keyboard {
+        Some(name) => {
+            let source = get_source(name)
+                .ok_or(AppError::KeyboardNotFoundError(name.clone()))?;
+            let keyboard_callback = enclose!(
+            (store_mutex, journal) move |event_list: &EventList, _context: &mut u32| {
+                if let Ok(store) = store_mutex.lock() {
+                    for event_packet in event_list.iter() {
+                        for data in event_packet.data() {
+                            let action = keyboard_to_action(*data);
+                            // Keyboards send clock and active sensing all
+                            // the time, none of which is worth keeping.
+                            if let Action::Noop = action {
+                                continue;
+                            }
+                            journal_action(&journal, &action);
+                            block_on(store.dispatch(action))
+                        }
+                    }
+                }
+            });
+            Some(listen_to_source(&client, &source, keyboard_callback)?)
+        }
+        None => None,
+    };
+    let instruments =
+        open_instruments(&client, &options.instrument, &starting_state.state)?;
+    let output = ChangedOutput::new(CaptureOutput {
+        inner: Box::new(PortOutput {
+            port: output_port,
+            dest,
+        }),
+        capture: capture.clone(),
+    });
     if let Ok(store) = store_mutex.lock() {
         // Set the grid to be the initial state.
-        state_to_device(&device, &output_port, &dest, &initial_state())?;
+        state_to_device(&device, &output, &starting_state.state)?;
+        output.flush()?;
         println!("Subscribing...");
         store
-            .subscribe(move |state: &GlobalState| {
-                state_to_device(&device, &output_port, &dest, state)
+            .subscribe(enclose!((device) move |undoable: &Undoable| {
+                state_to_device(&device, &output, &undoable.state)
+                    .and_then(|()| output.flush())
                     .unwrap_or_else(|err| {
                         println!("Error sending state to device: {:#?}", err);
                         ()
                     })
-            })
+            }))
             .await;
+        if !instruments.is_empty() {
+            let scheduler = Mutex::new(Scheduler::default());
+            store
+                .subscribe(move |undoable: &Undoable| {
+                    if let Ok(mut scheduler) = scheduler.lock() {
+                        scheduler
+                            .update(&instruments, &undoable.state)
+                            .unwrap_or_else(|err| {
+                                println!("Error playing notes: {:#?}", err);
+                            })
+                    }
+                })
+                .await;
+        }
+        if let Some(project) = project {
+            store
+                .subscribe(move |undoable: &Undoable| {
+                    if let Ok(mut project) = project.lock() {
+                        project.update(&undoable.state).unwrap_or_else(|err| {
+                            println!("Error saving project: {:#?}", err);
+                        })
+                    }
+                })
+                .await;
+        }
     }
     println!("Setting up timer...");
-    let _scheduler = thread::spawn(move || {
-        let duration = Duration::from_millis(1000);
+    let _scheduler = thread::spawn(enclose!((journal) move || {
+        let mut duration = Tempo::default().tick_duration();
         loop {
             println!("Time interval: Seeing if we can grab the mutex for the store...");
             if let Ok(store) = store_mutex.lock() {
                 println!("Pumping the interval...");
+                capture_event(&capture, CaptureEvent::Tick);
+                journal_tick(&journal);
                 block_on(store.dispatch(Action::TimeInterval));
+                // Keep up with the tempo as it is tapped.
+                duration = block_on(store.select(|undoable: &Undoable| {
+                    undoable.state.player.tempo.tick_duration()
+                }));
             }
             thread::sleep(duration);
         }
-    });
+    }));
     println!("Everything started up, waiting for input!");
-    thread::park();
+    match tokio::signal::ctrl_c().await {
+        // The journal is finished off on the way out, so a replay of it gets
+        // as far as the session did.
+        Ok(()) => journal_close(&journal),
+        Err(_) => thread::park(),
+    }
     Ok(())
 }
 
-fn note_to_device(
-    device: &dyn Device,
-    output_port: &OutputPort,
-    dest: &Destination,
-    interval: usize,
-    section_index: usize,
-    layer_index: usize,
-    note_interval: usize,
-    note: &Note,
-) -> Result<(), AppError> {
-    (0..8)
-        .map(|note_octave| {
-            (note_interval..8)
-                .map(|note_interval_by_length| {
-                    device.set_grid_button(
-                        &output_port,
-                        &dest,
-                        note_interval,
-                        note_octave,
-                        note_color(
-                            layer_index,
-                            section_index,
-                            interval,
-                            note_interval,
-                            &note,
-                            note_octave,
-                            note_interval_by_length,
-                        ),
-                    )
-                })
-                .collect::<Result<(), AppError>>()
-        })
-        .collect::<Result<(), AppError>>()
-}
-
-fn layer_to_device(
-    device: &dyn Device,
-    output_port: &OutputPort,
-    dest: &Destination,
-    interval: usize,
-    section_index: usize,
-    active_layer_index: usize,
-    layer_index: usize,
-    layer: &Layer,
-) -> Result<(), AppError> {
-    device
-        .set_layer_button(
-            &output_port,
-            &dest,
-            layer_index,
-            Color {
-                style: ColorStyle::Steady100,
-                rgb: active_color(layer_index, active_layer_index),
-            },
-        )
-        .and_then(|()| {
-            if layer_index == active_layer_index {
-                layer
-                    .notes
-                    .iter()
-                    .enumerate()
-                    .map(|(note_index, note)| {
-                        note_to_device(
-                            device,
-                            output_port,
-                            dest,
-                            interval,
-                            section_index,
-                            layer_index,
-                            note_index,
-                            &note,
-                        )
-                    })
-                    .collect::<Result<(), AppError>>()
-            } else {
-                Ok(())
-            }
-        })
-}
-
-fn section_to_device(
-    device: &dyn Device,
-    output_port: &OutputPort,
-    dest: &Destination,
-    interval: usize,
-    active_section_index: usize,
-    active_layer_index: usize,
-    section_index: usize,
-    section: &Section,
-) -> Result<(), AppError> {
-    device
-        .set_section_button(
-            &output_port,
-            &dest,
-            section_index,
-            Color {
-                style: ColorStyle::Steady100,
-                rgb: active_color(section_index, active_section_index),
-            },
-        )
-        .and_then(|()| {
-            if section_index == active_section_index {
-                section
-                    .layers
-                    .iter()
-                    .enumerate()
-                    .map(|(layer_index, layer)| {
-                        layer_to_device(
-                            device,
-                            output_port,
-                            dest,
-                            interval,
-                            section_index,
-                            active_layer_index,
-                            layer_index,
-                            &layer,
-                        )
-                    })
-                    .collect::<Result<(), AppError>>()
-            } else {
-                Ok(())
-            }
-        })
-}
-
-fn state_to_device(
-    device: &dyn Device,
-    output_port: &OutputPort,
-    dest: &Destination,
+// Layers play on the destination named after their instrument, if there is
+// one, and on the one given with --instrument otherwise.
+fn open_instruments(
+    client: &Client,
+    default: &Option<String>,
     state: &GlobalState,
-) -> Result<(), AppError> {
-    println!("State has changed...");
-    device
-        .set_play_button(
-            &output_port,
-            &dest,
-            play_mode_color(state.player.play_mode.clone()),
-        )
-        .and_then(|()| {
-            state
-                .sections
-                .iter()
-                .enumerate()
-                .map(|(section_index, section)| {
-                    section_to_device(
-                        device,
-                        output_port,
-                        dest,
-                        state.player.interval,
-                        state.player.active_section_index,
-                        state.player.active_layer_index,
-                        section_index,
-                        section,
-                    )
-                })
-                .collect::<Result<(), AppError>>()
-        })
+) -> Result<Instruments, AppError> {
+    let open = |name: &String| -> Result<Box<dyn MidiOutput + Send>, AppError> {
+        let dest = get_destination(name)
+            .ok_or(AppError::InstrumentNotFoundError(name.clone()))?;
+        let port = client
+            .output_port(&format!("grinstrument-instrument-port-{}", name))
+            .map_err(AppError::MidiPortError)?;
+        Ok(Box::new(PortOutput { port, dest }))
+    };
+    let names = state
+        .sections
+        .iter()
+        .flat_map(|section| &section.layers)
+        .map(|layer| &layer.instrument)
+        .filter(|name| get_destination(name).is_some())
+        .collect::<BTreeSet<&String>>();
+    Ok(Instruments {
+        default: default.as_ref().map(open).transpose()?,
+        named: names
+            .into_iter()
+            .map(|name| Ok((name.clone(), open(name)?)))
+            .collect::<Result<HashMap<_, _>, AppError>>()?,
+    })
 }
 
-// Order dictates the layer.
-const LAYER_COLORS: &[u32] = &[
-    0x0000ff, 0x00ffff, 0x00ff00, 0xffff00, 0xff0000, 0xff00ff, 0xffaa00,
-    0xffffff,
-];
-
-fn active_color(current: usize, active: usize) -> u32 {
-    if current == active {
-        1
-    } else {
-        0
+fn journal_tick(journal: &Option<Arc<Mutex<Journal>>>) {
+    if let Some(journal_mutex) = journal {
+        if let Ok(mut journal) = journal_mutex.lock() {
+            journal.tick();
+        }
     }
 }
 
-// TODO: Consider not using Color here since the color style is discarded in the
-// device.
-fn note_color(
-    layer: usize,
-    section: usize,
-    interval: usize,
-    note_index: usize,
-    note: &Note,
-    octave: usize,
-    length_pos: usize,
-) -> Color {
-    // TODO: Do not hardcode section sizes.
-    if interval == note_index + (section * 8) {
-        // Active note and interval.
-        if note.length > 0 && note.octaves.contains(&octave) {
-            Color {
-                rgb: LAYER_COLORS[layer],
-                style: ColorStyle::Steady95,
-            }
-            // Interval active here.
-        } else {
-            Color {
-                rgb: LAYER_COLORS[layer],
-                style: ColorStyle::Steady50,
-            }
-        }
-        // Active note with nothing else.
-    } else if note.length > 0 && note.octaves.contains(&octave) {
-        if length_pos == 0 {
-            // Where the note begins.
-            Color {
-                rgb: LAYER_COLORS[layer],
-                style: ColorStyle::Steady75,
-            }
-        } else {
-            // Any part of a longer note.
-            Color {
-                rgb: LAYER_COLORS[layer],
-                style: ColorStyle::Steady65,
-            }
-        }
-        // Vacant.
-    } else {
-        Color {
-            rgb: 0,
-            style: ColorStyle::Steady100,
+fn journal_close(journal: &Option<Arc<Mutex<Journal>>>) {
+    if let Some(journal_mutex) = journal {
+        if let Ok(mut journal) = journal_mutex.lock() {
+            journal.close().unwrap_or_else(|err| {
+                println!("Error writing to journal: {:#?}", err);
+            })
         }
     }
 }
 
-fn play_mode_color(play_mode: PlayMode) -> Color {
-    match play_mode {
-        PlayMode::Playing => Color {
-            rgb: 0x1,
-            style: ColorStyle::Steady100,
-        },
-        PlayMode::Paused => Color {
-            rgb: 0x1,
-            style: ColorStyle::Blink2,
-        },
-        PlayMode::Stopped => Color {
-            rgb: 0x0,
-            style: ColorStyle::Steady100,
-        },
+fn journal_action(journal: &Option<Arc<Mutex<Journal>>>, action: &Action) {
+    if let Some(journal_mutex) = journal {
+        if let Ok(mut journal) = journal_mutex.lock() {
+            journal.append(action).unwrap_or_else(|err| {
+                println!("Error writing to journal: {:#?}", err);
+            })
+        }
     }
 }
 
@@ -1,7 +1,7 @@
-use crate::error::AppError;
+use crate::{device::MidiOutput, error::AppError};
 use coremidi::{
-    Client, Destination, Destinations, EventList, InputPortWithContext,
-    OutputPort, Protocol, Source, Sources,
+    Client, Destination, Destinations, EventBuffer, EventList,
+    InputPortWithContext, OutputPort, Protocol, Source, Sources,
 };
 
 macro_rules! endpoint_names {
@@ -60,6 +60,28 @@ pub fn connect_to_controller<
     }
 }
 
+/**
+ * Listen to a source other than the controller, such as a keyboard.
+ */
+pub fn listen_to_source<F: FnMut(&EventList, &mut u32) + Send + 'static>(
+    client: &Client,
+    source: &Source,
+    callback: F,
+) -> Result<InputPortWithContext<u32>, AppError> {
+    let source_id = source.unique_id().ok_or(AppError::SourceUniqueIdError)?;
+    let mut input_port = client
+        .input_port_with_protocol(
+            &format!("grinstrument-input-port-{}", source_id),
+            Protocol::Midi10,
+            callback,
+        )
+        .map_err(AppError::MidiPortError)?;
+    input_port
+        .connect_source(source, source_id)
+        .map_err(AppError::SourceListenError)?;
+    Ok(input_port)
+}
+
 pub fn get_destination(name: &str) -> Option<Destination> {
     Destinations
         .into_iter()
@@ -75,3 +97,21 @@ pub fn get_source(name: &str) -> Option<Source> {
         None => false,
     })
 }
+
+/**
+ * Sends packets out of a port to a single destination.
+ */
+pub struct PortOutput {
+    pub port: OutputPort,
+    pub dest: Destination,
+}
+
+impl MidiOutput for PortOutput {
+    fn send(&self, packet: u32) -> Result<(), AppError> {
+        let event =
+            EventBuffer::new(Protocol::Midi10).with_packet(0, &[packet]);
+        self.port
+            .send(&self.dest, &event)
+            .map_err(AppError::OutputSendError)
+    }
+}
@@ -0,0 +1,135 @@
+use crate::follow::follow_layers;
+use crate::playback::morph_rolls;
+use crate::state::{GlobalState, Layer, Note, Section};
+use std::borrow::Cow;
+
+/**
+ * A crossfade from the active section to another. Each step of each layer
+ * plays from one section or the other, from the other more often the further
+ * along the morph is.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Morph {
+    // The section morphed towards, once one has been picked.
+    pub section: Option<usize>,
+    // How far along the morph is, as a percentage.
+    pub amount: usize,
+}
+
+/**
+ * A section as it is played. The active section is part way to the section it
+ * is morphing towards, and everything else is played as it is.
+ */
+pub fn played_section(
+    state: &GlobalState,
+    section_index: usize,
+) -> Option<Cow<'_, Section>> {
+    let morph = &state.player.morph;
+    let section = state.sections.get(section_index)?;
+    match morph.section.and_then(|target| state.sections.get(target)) {
+        Some(target)
+            if section_index == state.player.active_section_index
+                && morph.amount > 0 =>
+        {
+            let mut morphed = Section {
+                layers: section
+                    .layers
+                    .iter()
+                    .enumerate()
+                    .map(|(layer_index, layer)| {
+                        morphed_layer(
+                            state,
+                            section_index,
+                            layer_index,
+                            layer,
+                            target,
+                        )
+                    })
+                    .collect(),
+            };
+            // Followers follow the morph, rather than either section.
+            follow_layers(&state.settings, &mut morphed);
+            Some(Cow::Owned(morphed))
+        }
+        _ => Some(Cow::Borrowed(section)),
+    }
+}
+
+// The steps of a layer, each drawn from it or from the same layer of the
+// section morphed towards. Notes drawn across keep their pitch, and are
+// dropped where the layer has no row for it.
+fn morphed_layer(
+    state: &GlobalState,
+    section_index: usize,
+    layer_index: usize,
+    layer: &Layer,
+    target: &Section,
+) -> Layer {
+    let settings = &state.settings;
+    let other = target.layers.get(layer_index);
+    let rolls =
+        morph_rolls(settings, section_index, layer_index, layer.notes.len());
+    Layer {
+        notes: layer
+            .notes
+            .iter()
+            .zip(rolls)
+            .enumerate()
+            .map(|(step, (note, roll))| {
+                match other
+                    .as_ref()
+                    .filter(|_| roll < state.player.morph.amount)
+                {
+                    Some(other) => other
+                        .notes
+                        .get(step)
+                        .map(|other_note| Note {
+                            octaves: other_note
+                                .octaves
+                                .iter()
+                                .filter_map(|row| other.pitch(*row))
+                                .filter_map(|pitch| layer.row(pitch))
+                                .collect(),
+                            ..other_note.clone()
+                        })
+                        .unwrap_or_default(),
+                    None => note.clone(),
+                }
+            })
+            .collect(),
+        ..layer.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{initial_state, Settings};
+
+    fn morphing(amount: usize) -> GlobalState {
+        let mut state = initial_state(Settings::default());
+        state.sections[0].layers[0].notes[0].octaves = vec![60];
+        state.sections[1].layers[0].notes[1].octaves = vec![62];
+        state.player.morph = Morph {
+            section: Some(1),
+            amount,
+        };
+        state
+    }
+
+    #[test]
+    fn the_active_section_plays_all_of_a_finished_morph() {
+        let state = morphing(100);
+        let section = played_section(&state, 0).unwrap();
+        assert!(section.layers[0].notes[0].octaves.is_empty());
+        assert_eq!(section.layers[0].notes[1].octaves, vec![62]);
+    }
+
+    #[test]
+    fn only_the_active_section_morphs() {
+        let state = morphing(100);
+        assert!(matches!(played_section(&state, 1), Some(Cow::Borrowed(_))));
+        let state = morphing(0);
+        assert!(matches!(played_section(&state, 0), Some(Cow::Borrowed(_))));
+    }
+}
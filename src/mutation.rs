@@ -0,0 +1,200 @@
+use crate::state::{Layer, LayerKind, Settings};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/**
+ * How a layer evolves as it plays. Every so many loops each step gets a chance
+ * of changing, by the percentages here. A frozen layer keeps what it has.
+ */
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Mutation {
+    // How many loops go by between mutations.
+    pub every: usize,
+    // Empty steps given a note.
+    pub add: usize,
+    // Steps with notes emptied.
+    pub remove: usize,
+    // Steps swapped with the step beside them.
+    pub shift: usize,
+    // Notes moved a degree of the scale up or down. Drum voices stay put.
+    pub pitch: usize,
+    pub frozen: bool,
+}
+
+impl Default for Mutation {
+    fn default() -> Self {
+        Mutation {
+            every: 1,
+            add: 0,
+            remove: 0,
+            shift: 0,
+            pitch: 0,
+            frozen: false,
+        }
+    }
+}
+
+pub const MAX_MUTATION_EVERY: usize = 8;
+
+impl Mutation {
+    pub fn is_active(&self) -> bool {
+        !self.frozen && self.add + self.remove + self.shift + self.pitch > 0
+    }
+}
+
+/**
+ * Mutates the steps of a layer once. New notes only ever go on rows the layer
+ * already uses, so a layer doesn't wander off to notes nobody wrote.
+ */
+pub fn mutate<R: Rng>(layer: &mut Layer, settings: &Settings, rng: &mut R) {
+    let mutation = layer.mutation;
+    let length = layer.length.min(layer.notes.len());
+    let row_count = layer.row_count(settings);
+    let pitched = match layer.kind {
+        // Followers have no steps of their own to mutate.
+        LayerKind::Follower => return,
+        LayerKind::Melodic | LayerKind::Arpeggio => true,
+        LayerKind::Drum { .. } => false,
+    };
+    let mut rows = layer
+        .notes
+        .iter()
+        .take(length)
+        .flat_map(|note| note.octaves.iter().copied())
+        .collect::<Vec<usize>>();
+    rows.sort();
+    rows.dedup();
+    for step in 0..length {
+        let note = &mut layer.notes[step];
+        if note.octaves.is_empty() {
+            if !rows.is_empty() && rng.gen_range(0..100) < mutation.add {
+                note.octaves.push(rows[rng.gen_range(0..rows.len())]);
+                note.length = note.length.max(1);
+            }
+        } else if rng.gen_range(0..100) < mutation.remove {
+            note.octaves.clear();
+        } else if pitched && rng.gen_range(0..100) < mutation.pitch {
+            let degrees = if rng.gen_bool(0.5) { 1 } else { -1 };
+            note.octaves = note
+                .octaves
+                .iter()
+                .map(|row| {
+                    u8::try_from(*row)
+                        .ok()
+                        .and_then(|pitch| settings.scale.shift(pitch, degrees))
+                        .map(usize::from)
+                        .filter(|row| *row < row_count)
+                        .unwrap_or(*row)
+                })
+                .collect();
+        }
+        if length > 1 && rng.gen_range(0..100) < mutation.shift {
+            let other = if rng.gen_bool(0.5) {
+                (step + 1) % length
+            } else {
+                (step + length - 1) % length
+            };
+            layer.notes.swap(step, other);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{initial_state, Note};
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    fn layer_with_rows(settings: &Settings, rows: &[Option<usize>]) -> Layer {
+        let mut layer =
+            initial_state(settings.clone()).sections[0].layers[0].clone();
+        layer.notes = rows
+            .iter()
+            .map(|row| Note {
+                octaves: row.iter().copied().collect(),
+                length: row.map_or(0, |_| 1),
+                ..Note::default()
+            })
+            .collect();
+        layer.length = rows.len();
+        layer
+    }
+
+    #[test]
+    fn adding_only_uses_rows_already_there() {
+        let settings = Settings::default();
+        let mut layer =
+            layer_with_rows(&settings, &[Some(60), None, Some(64), None]);
+        layer.mutation.add = 100;
+        mutate(&mut layer, &settings, &mut ChaCha8Rng::seed_from_u64(0));
+        assert!(layer.notes.iter().all(|note| {
+            note.octaves.len() == 1 && [60, 64].contains(&note.octaves[0])
+        }));
+    }
+
+    #[test]
+    fn removing_empties_steps() {
+        let settings = Settings::default();
+        let mut layer = layer_with_rows(&settings, &[Some(60), Some(62)]);
+        layer.mutation.remove = 100;
+        mutate(&mut layer, &settings, &mut ChaCha8Rng::seed_from_u64(0));
+        assert!(layer.notes.iter().all(|note| note.octaves.is_empty()));
+    }
+
+    #[test]
+    fn pitches_move_a_degree_of_the_scale() {
+        let settings = Settings::default();
+        let mut layer = layer_with_rows(&settings, &[Some(60); 8]);
+        layer.mutation.pitch = 100;
+        mutate(&mut layer, &settings, &mut ChaCha8Rng::seed_from_u64(0));
+        assert!(layer
+            .notes
+            .iter()
+            .all(|note| [59, 62].contains(&note.octaves[0])));
+    }
+
+    #[test]
+    fn the_same_seed_mutates_the_same_way() {
+        let settings = Settings::default();
+        let mut layer =
+            layer_with_rows(&settings, &[Some(60), None, Some(67), None]);
+        layer.mutation = Mutation {
+            add: 50,
+            remove: 50,
+            shift: 50,
+            pitch: 50,
+            ..Mutation::default()
+        };
+        let mut other = layer.clone();
+        mutate(&mut layer, &settings, &mut ChaCha8Rng::seed_from_u64(5));
+        mutate(&mut other, &settings, &mut ChaCha8Rng::seed_from_u64(5));
+        assert!(layer == other);
+    }
+
+    #[test]
+    fn frozen_layers_are_left_alone() {
+        let mutation = Mutation {
+            add: 100,
+            frozen: true,
+            ..Mutation::default()
+        };
+        assert!(!mutation.is_active());
+        assert!(Mutation {
+            frozen: false,
+            ..mutation
+        }
+        .is_active());
+    }
+
+    #[test]
+    fn followers_are_left_alone() {
+        let settings = Settings::default();
+        let mut layer = layer_with_rows(&settings, &[Some(60), None]);
+        layer.kind = LayerKind::Follower;
+        layer.mutation.add = 100;
+        let notes = layer.notes.clone();
+        mutate(&mut layer, &settings, &mut ChaCha8Rng::seed_from_u64(0));
+        assert_eq!(layer.notes, notes);
+    }
+}
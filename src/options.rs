@@ -0,0 +1,184 @@
+use crate::error::AppError;
+use crate::scale::Scale;
+use crate::state::Settings;
+use std::path::PathBuf;
+
+/**
+ * Options given on the command line. Everything is optional. With no options
+ * at all we just connect to the controller and start from a blank slate.
+ */
+#[derive(Default)]
+pub struct Options {
+    // Record the raw MIDI traffic with the controller to this file. Captures
+    // start from a blank slate, so this can't be used with a replay.
+    pub capture: Option<PathBuf>,
+    // Check a capture against what we would send today, with no controller
+    // connected, and exit.
+    pub check_capture: Option<PathBuf>,
+    // Bind the rows of drum layers to the voices in this file.
+    pub drum_map: Option<PathBuf>,
+    // Lay the groove in this file over everything played.
+    pub groove: Option<PathBuf>,
+    // Play the pattern out to the MIDI destination with this name, apart from
+    // layers whose instrument is a destination of its own.
+    pub instrument: Option<String>,
+    // Record from the MIDI keyboard with this name.
+    pub keyboard: Option<String>,
+    // Append every dispatched action to this file, after the sessions already
+    // in it.
+    pub journal: Option<PathBuf>,
+    // Open the project in this file, if there is one, and keep it saved there
+    // as it changes.
+    pub project: Option<PathBuf>,
+    // Rebuild the starting state from the last session in this journal,
+    // starting from the project it started from. The journal must have been
+    // made with the same settings.
+    pub replay: Option<PathBuf>,
+    pub settings: Settings,
+}
+
+fn option_value<I: Iterator<Item = String>>(
+    name: &str,
+    args: &mut I,
+) -> Result<PathBuf, AppError> {
+    args.next()
+        .map(PathBuf::from)
+        .ok_or_else(|| AppError::MissingOptionValueError(name.to_string()))
+}
+
+fn option_number<I: Iterator<Item = String>>(
+    name: &str,
+    args: &mut I,
+) -> Result<u64, AppError> {
+    args.next()
+        .ok_or_else(|| AppError::MissingOptionValueError(name.to_string()))
+        .and_then(|value| {
+            value.parse::<u64>().map_err(|_| {
+                AppError::InvalidOptionValueError(name.to_string(), value)
+            })
+        })
+}
+
+// Sizes of things. Zero of anything isn't a project.
+fn option_size<I: Iterator<Item = String>>(
+    name: &str,
+    args: &mut I,
+) -> Result<usize, AppError> {
+    args.next()
+        .ok_or_else(|| AppError::MissingOptionValueError(name.to_string()))
+        .and_then(|value| {
+            value.parse::<usize>().ok().filter(|x| *x > 0).ok_or(
+                AppError::InvalidOptionValueError(name.to_string(), value),
+            )
+        })
+}
+
+pub fn parse_options<I: Iterator<Item = String>>(
+    mut args: I,
+) -> Result<Options, AppError> {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--capture" => {
+                options.capture = Some(option_value(&arg, &mut args)?)
+            }
+            "--check-capture" => {
+                options.check_capture = Some(option_value(&arg, &mut args)?)
+            }
+            "--drum-map" => {
+                options.drum_map = Some(option_value(&arg, &mut args)?)
+            }
+            "--groove" => options.groove = Some(option_value(&arg, &mut args)?),
+            "--instrument" => {
+                options.instrument = Some(
+                    option_value(&arg, &mut args)?
+                        .to_string_lossy()
+                        .to_string(),
+                )
+            }
+            "--journal" => {
+                options.journal = Some(option_value(&arg, &mut args)?)
+            }
+            "--keyboard" => {
+                options.keyboard = Some(
+                    option_value(&arg, &mut args)?
+                        .to_string_lossy()
+                        .to_string(),
+                )
+            }
+            "--layers" => {
+                options.settings.layer_count = option_size(&arg, &mut args)?
+            }
+            "--project" => {
+                options.project = Some(option_value(&arg, &mut args)?)
+            }
+            "--replay" => options.replay = Some(option_value(&arg, &mut args)?),
+            "--rows" => {
+                options.settings.row_count = option_size(&arg, &mut args)?
+            }
+            "--section-length" => {
+                options.settings.section_length = option_size(&arg, &mut args)?
+            }
+            "--scale" => {
+                let name = option_value(&arg, &mut args)?
+                    .to_string_lossy()
+                    .to_string();
+                options.settings.scale = Scale::from_name(&name)
+                    .ok_or(AppError::InvalidOptionValueError(arg, name))?
+            }
+            "--seed" => options.settings.seed = option_number(&arg, &mut args)?,
+            "--sections" => {
+                options.settings.section_count = option_size(&arg, &mut args)?
+            }
+            "--swing" => {
+                let swing = option_number(&arg, &mut args)?;
+                options.settings.swing = (50..=75)
+                    .contains(&swing)
+                    .then_some(swing as usize)
+                    .ok_or(AppError::InvalidOptionValueError(
+                        arg,
+                        swing.to_string(),
+                    ))?
+            }
+            _ => return Err(AppError::UnknownOptionError(arg)),
+        }
+    }
+    if options.capture.is_some() && options.replay.is_some() {
+        return Err(AppError::ConflictingOptionsError(
+            "--capture".to_string(),
+            "--replay".to_string(),
+        ));
+    }
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, AppError> {
+        parse_options(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn sizes_must_be_more_than_nothing() {
+        assert_eq!(parse(&["--rows", "12"]).unwrap().settings.row_count, 12);
+        assert!(matches!(
+            parse(&["--rows", "0"]),
+            Err(AppError::InvalidOptionValueError(..))
+        ));
+        assert!(matches!(
+            parse(&["--rows"]),
+            Err(AppError::MissingOptionValueError(_))
+        ));
+    }
+
+    #[test]
+    fn captures_are_not_made_from_replays() {
+        assert!(parse(&["--capture", "a.txt"]).is_ok());
+        assert!(matches!(
+            parse(&["--capture", "a.txt", "--replay", "b.json"]),
+            Err(AppError::ConflictingOptionsError(..))
+        ));
+    }
+}
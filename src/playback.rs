@@ -0,0 +1,727 @@
+use crate::state::{ArpeggioMode, Condition, Direction, Layer, Note, Settings};
+use rand::{Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+// How many ticks of the clock make up a step. Layers running faster or slower
+// than the clock, and anything else that lands between steps, need something
+// finer than a step to go by. Twelve divides evenly by two, three and four.
+pub const TICKS_PER_STEP: usize = 12;
+
+/**
+ * How many steps a layer has taken some number of ticks into its section. Only
+ * the active section plays, from the top whenever it is picked or playing
+ * starts, and each of its layers loops on its own length for as long as it
+ * plays. Each layer runs at its own rate too, so layers drift against one
+ * another.
+ */
+pub fn layer_steps_taken(layer: &Layer, tick: usize) -> usize {
+    tick * layer.rate.multiplier / (layer.rate.divider * TICKS_PER_STEP)
+}
+
+/**
+ * How many ticks a step of a layer lasts, give or take a tick for rates that
+ * don't divide evenly.
+ */
+pub fn step_ticks(layer: &Layer) -> usize {
+    (layer.rate.divider * TICKS_PER_STEP / layer.rate.multiplier).max(1)
+}
+
+/**
+ * The tick into a section on which a layer takes a step.
+ */
+pub fn step_start_tick(layer: &Layer, steps_taken: usize) -> usize {
+    (steps_taken * layer.rate.divider * TICKS_PER_STEP)
+        .div_ceil(layer.rate.multiplier)
+}
+
+// What the random numbers of a layer go towards. Each gets streams of its own,
+// so a condition rolling the dice doesn't change where a random layer goes.
+// The numbers pick the streams, so they stay as they are for seeds to keep
+// playing the same way, and anything new goes on the end.
+enum Stream {
+    Direction = 0,
+    Condition = 1,
+    Arpeggio = 2,
+    Mutation = 3,
+    Morph = 4,
+}
+
+// Every layer of every section gets a stream of its own for each of them too,
+// so changing one layer doesn't shake up the rest. What the stream is for sits
+// above the layer, so adding another never moves the streams already there.
+fn layer_rng(
+    settings: &Settings,
+    section_index: usize,
+    layer_index: usize,
+    stream: Stream,
+) -> ChaCha8Rng {
+    let mut rng = ChaCha8Rng::seed_from_u64(settings.seed);
+    let layer = section_index * settings.layer_count + layer_index;
+    rng.set_stream((stream as u64) << 32 | layer as u64);
+    rng
+}
+
+/**
+ * What a layer mutates with once it has looped some number of times. Each
+ * mutation draws from a place of its own, so the same seed mutates a layer the
+ * same way every time.
+ */
+pub fn mutation_rng(
+    settings: &Settings,
+    section_index: usize,
+    layer_index: usize,
+    loop_count: usize,
+) -> ChaCha8Rng {
+    let mut rng =
+        layer_rng(settings, section_index, layer_index, Stream::Mutation);
+    rng.set_word_pos((loop_count as u128) << 32);
+    rng
+}
+
+/**
+ * Where each step of a layer stands in a morph, from 0 to 99. A step plays from
+ * the section morphed towards once the morph is further along than its roll, so
+ * pushing the fader up only ever brings more steps across.
+ */
+pub fn morph_rolls(
+    settings: &Settings,
+    section_index: usize,
+    layer_index: usize,
+    steps: usize,
+) -> Vec<usize> {
+    let mut rng =
+        layer_rng(settings, section_index, layer_index, Stream::Morph);
+    (0..steps).map(|_| rng.gen_range(0..100)).collect()
+}
+
+/**
+ * The step a layer lands on after taking some number of steps. The random
+ * directions draw from a place of their own for each step taken, so wherever
+ * we look from, the same seed gives the same steps.
+ */
+pub fn layer_step(
+    settings: &Settings,
+    section_index: usize,
+    layer_index: usize,
+    layer: &Layer,
+    steps_taken: usize,
+) -> usize {
+    let length = layer.length.max(1);
+    match layer.direction {
+        Direction::Forward => steps_taken % length,
+        Direction::Reverse => length - 1 - steps_taken % length,
+        Direction::PingPong => {
+            let period = (length * 2).saturating_sub(2).max(1);
+            let step = steps_taken % period;
+            if step < length {
+                step
+            } else {
+                period - step
+            }
+        }
+        Direction::Random => {
+            let mut rng = layer_rng(
+                settings,
+                section_index,
+                layer_index,
+                Stream::Direction,
+            );
+            rng.set_word_pos(steps_taken as u128);
+            rng.next_u32() as usize % length
+        }
+        // The walk starts over from the first step each time the layer
+        // loops, so finding where it is never takes more than a loop of
+        // draws.
+        Direction::Brownian => {
+            let mut rng = layer_rng(
+                settings,
+                section_index,
+                layer_index,
+                Stream::Direction,
+            );
+            let loop_start = steps_taken - steps_taken % length;
+            rng.set_word_pos(loop_start as u128);
+            (loop_start..steps_taken).fold(0, |step, _| {
+                (step + length + rng.next_u32() as usize % 3 - 1) % length
+            })
+        }
+    }
+}
+
+/**
+ * How many times a layer of the active section has come back around to its
+ * first step since the section started playing, as of some number of steps
+ * taken. Steps with a condition on them go by this.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LayerLoops {
+    pub count: usize,
+    pub steps_taken: usize,
+    // Whether the last step with a condition before the step at from was
+    // played, if there was one. NotPrevious goes on from here rather than
+    // going all the way back to the start.
+    pub from: usize,
+    pub previous: Option<bool>,
+}
+
+impl LayerLoops {
+    // The loop some number of steps taken falls on, going by the loop counted
+    // last. Steps either side of the playhead can be on the loops either side.
+    pub fn loop_of(&self, layer: &Layer, steps_taken: usize) -> usize {
+        let length = layer.length.max(1);
+        (self.count + steps_taken / length)
+            .saturating_sub(self.steps_taken / length)
+    }
+}
+
+/**
+ * A layer of the active section as it is being played, which is what the
+ * conditions on its steps go by.
+ */
+pub struct PlayingLayer<'a> {
+    pub settings: &'a Settings,
+    pub fill: bool,
+    pub section_index: usize,
+    pub layer_index: usize,
+    pub layer: &'a Layer,
+    pub loops: &'a LayerLoops,
+}
+
+/**
+ * The loops of a layer once it has taken some number of steps, going on from
+ * the loops counted so far. Coming back around to the first step counts
+ * another loop, and works out how the steps with a condition went up to the
+ * start of the loop just finished.
+ */
+pub fn count_loops(
+    settings: &Settings,
+    fill: bool,
+    section_index: usize,
+    layer_index: usize,
+    layer: &Layer,
+    loops: &LayerLoops,
+    steps_taken: usize,
+) -> LayerLoops {
+    let length = layer.length.max(1);
+    if steps_taken > loops.steps_taken && steps_taken.is_multiple_of(length) {
+        let from = (steps_taken - length).max(loops.from);
+        LayerLoops {
+            count: loops.count + 1,
+            steps_taken,
+            from,
+            previous: previous_fired(
+                &PlayingLayer {
+                    settings,
+                    fill,
+                    section_index,
+                    layer_index,
+                    layer,
+                    loops,
+                },
+                from,
+            ),
+        }
+    } else {
+        LayerLoops {
+            steps_taken,
+            ..*loops
+        }
+    }
+}
+
+// The condition of the step a layer lands on after taking some number of
+// steps. Empty steps don't play anyway, so they have none.
+fn step_condition(
+    settings: &Settings,
+    section_index: usize,
+    layer_index: usize,
+    layer: &Layer,
+    steps_taken: usize,
+) -> Condition {
+    let step =
+        layer_step(settings, section_index, layer_index, layer, steps_taken);
+    layer
+        .notes
+        .get(step)
+        .filter(|note| note.length > 0)
+        .map_or(Condition::Always, |note| note.condition)
+}
+
+// Whether a step with a condition is played, given whether the last step with
+// a condition before it was.
+fn condition_fires(
+    playing: &PlayingLayer,
+    steps_taken: usize,
+    condition: Condition,
+    previous: Option<bool>,
+) -> bool {
+    let PlayingLayer {
+        settings,
+        fill,
+        section_index,
+        layer_index,
+        layer,
+        loops,
+    } = *playing;
+    let loop_count = loops.loop_of(layer, steps_taken);
+    match condition {
+        Condition::Always => true,
+        Condition::Chance { percent } => {
+            let mut rng = layer_rng(
+                settings,
+                section_index,
+                layer_index,
+                Stream::Condition,
+            );
+            rng.set_word_pos(steps_taken as u128);
+            ((rng.next_u32() % 100) as usize) < percent
+        }
+        Condition::Every { nth, of } => loop_count % of.max(1) + 1 == nth,
+        Condition::Fill => fill,
+        Condition::First => loop_count == 0,
+        Condition::NotPrevious => previous != Some(true),
+    }
+}
+
+// Whether the last step with a condition before some number of steps taken
+// was played, going forward from where the loops left off.
+fn previous_fired(playing: &PlayingLayer, steps_taken: usize) -> Option<bool> {
+    let loops = playing.loops;
+    (loops.from..steps_taken).fold(loops.previous, |previous, x| {
+        match step_condition(
+            playing.settings,
+            playing.section_index,
+            playing.layer_index,
+            playing.layer,
+            x,
+        ) {
+            Condition::Always => previous,
+            condition => Some(condition_fires(playing, x, condition, previous)),
+        }
+    })
+}
+
+/**
+ * Whether the step a layer lands on after taking some number of steps is
+ * played, going by its condition. Chances are rolled from the seed, one roll
+ * for each step taken, so the same seed plays the same steps.
+ */
+pub fn step_fires(playing: &PlayingLayer, steps_taken: usize) -> bool {
+    let condition = step_condition(
+        playing.settings,
+        playing.section_index,
+        playing.layer_index,
+        playing.layer,
+        steps_taken,
+    );
+    let previous = match condition {
+        Condition::NotPrevious => previous_fired(playing, steps_taken),
+        _ => None,
+    };
+    condition_fires(playing, steps_taken, condition, previous)
+}
+
+/**
+ * How many ticks each note of an arpeggio lasts.
+ */
+pub fn arpeggio_ticks(layer: &Layer) -> usize {
+    let rate = layer.arpeggio.rate;
+    (rate.divider * TICKS_PER_STEP / rate.multiplier).max(1)
+}
+
+/**
+ * The row an arpeggio plays on its nth note into a step, and how many octaves
+ * up it plays it. Random arpeggios draw a note for each one played, from the
+ * seed, so the same seed plays the same notes.
+ */
+pub fn arpeggio_note(
+    settings: &Settings,
+    section_index: usize,
+    layer_index: usize,
+    layer: &Layer,
+    note: &Note,
+    steps_taken: usize,
+    n: usize,
+) -> Option<(usize, usize)> {
+    let mut rows = note.octaves.clone();
+    if layer.arpeggio.mode != ArpeggioMode::AsPlayed {
+        rows.sort();
+    }
+    let count = rows.len() * layer.arpeggio.octaves.max(1);
+    let position = match layer.arpeggio.mode {
+        ArpeggioMode::Up | ArpeggioMode::AsPlayed => n % count.max(1),
+        ArpeggioMode::Down => count.saturating_sub(1 + n % count.max(1)),
+        ArpeggioMode::UpDown => {
+            let period = (count * 2).saturating_sub(2).max(1);
+            let position = n % period;
+            if position < count {
+                position
+            } else {
+                period - position
+            }
+        }
+        ArpeggioMode::Random => {
+            let mut rng = layer_rng(
+                settings,
+                section_index,
+                layer_index,
+                Stream::Arpeggio,
+            );
+            // Each step gets plenty of room to itself.
+            rng.set_word_pos(((steps_taken as u128) << 32) + n as u128);
+            rng.next_u32() as usize % count.max(1)
+        }
+    };
+    rows.get(position % rows.len().max(1))
+        .map(|row| (*row, position / rows.len()))
+}
+
+/**
+ * Where an arpeggio layer is in its arpeggio some number of ticks into its
+ * section, if it is playing one, as the step it is playing the chord of and the
+ * row it is on. Like the playhead, this goes by the clock, leaving out swing
+ * and nudges.
+ */
+pub fn arpeggio_position(
+    settings: &Settings,
+    fill: bool,
+    section_index: usize,
+    layer_index: usize,
+    layer: &Layer,
+    loops: &LayerLoops,
+    tick: usize,
+) -> Option<(usize, usize)> {
+    let longest = layer.notes.iter().map(|note| note.length).max();
+    (0..=layer_steps_taken(layer, tick))
+        .rev()
+        .take(longest.unwrap_or(0))
+        .find_map(|steps_taken| {
+            let step = layer_step(
+                settings,
+                section_index,
+                layer_index,
+                layer,
+                steps_taken,
+            );
+            let start = step_start_tick(layer, steps_taken);
+            layer
+                .notes
+                .get(step)
+                .filter(|note| tick < start + note.length * step_ticks(layer))
+                .filter(|_| {
+                    step_fires(
+                        &PlayingLayer {
+                            settings,
+                            fill,
+                            section_index,
+                            layer_index,
+                            layer,
+                            loops,
+                        },
+                        steps_taken,
+                    )
+                })
+                .and_then(|note| {
+                    arpeggio_note(
+                        settings,
+                        section_index,
+                        layer_index,
+                        layer,
+                        note,
+                        steps_taken,
+                        (tick - start) / arpeggio_ticks(layer),
+                    )
+                })
+                .map(|(row, _)| (step, row))
+        })
+}
+
+/**
+ * The step of a layer closest to the playhead, some number of ticks into its
+ * section. Anything played past the middle of a step is pulled forward to the
+ * next.
+ */
+pub fn nearest_step(
+    settings: &Settings,
+    section_index: usize,
+    layer_index: usize,
+    layer: &Layer,
+    tick: usize,
+) -> usize {
+    layer_step(
+        settings,
+        section_index,
+        layer_index,
+        layer,
+        layer_steps_taken(layer, tick + step_ticks(layer) / 2),
+    )
+}
+
+/**
+ * Where the playhead of a layer sits, some number of ticks into its section.
+ */
+pub fn playhead_step(
+    settings: &Settings,
+    section_index: usize,
+    layer_index: usize,
+    layer: &Layer,
+    tick: usize,
+) -> usize {
+    layer_step(
+        settings,
+        section_index,
+        layer_index,
+        layer,
+        layer_steps_taken(layer, tick),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{initial_state, ClockRate};
+
+    // A layer with a note on every step, each with its own condition.
+    fn conditional_layer(
+        settings: &Settings,
+        conditions: &[Condition],
+    ) -> Layer {
+        let mut layer =
+            initial_state(settings.clone()).sections[0].layers[0].clone();
+        layer.length = conditions.len();
+        layer.notes = conditions
+            .iter()
+            .map(|condition| Note {
+                octaves: vec![60],
+                length: 1,
+                condition: *condition,
+                ..Note::default()
+            })
+            .collect();
+        layer
+    }
+
+    // Whether each step is played, one after another, counting the loops as
+    // the layer goes.
+    fn fired(settings: &Settings, layer: &Layer, steps: usize) -> Vec<bool> {
+        let mut loops = LayerLoops::default();
+        (0..steps)
+            .map(|steps_taken| {
+                loops = count_loops(
+                    settings,
+                    false,
+                    0,
+                    0,
+                    layer,
+                    &loops,
+                    steps_taken,
+                );
+                let playing = PlayingLayer {
+                    settings,
+                    fill: false,
+                    section_index: 0,
+                    layer_index: 0,
+                    layer,
+                    loops: &loops,
+                };
+                step_fires(&playing, steps_taken)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chance_is_the_same_for_the_same_seed() {
+        let settings = Settings {
+            seed: 7,
+            ..Settings::default()
+        };
+        let layer =
+            conditional_layer(&settings, &[Condition::Chance { percent: 50 }]);
+        let first = fired(&settings, &layer, 200);
+        assert_eq!(first, fired(&settings, &layer, 200));
+        let played = first.iter().filter(|x| **x).count();
+        assert!((70..130).contains(&played), "played {}", played);
+        let other = Settings {
+            seed: 8,
+            ..Settings::default()
+        };
+        assert_ne!(first, fired(&other, &layer, 200));
+    }
+
+    #[test]
+    fn streams_keep_their_place() {
+        let settings = Settings {
+            seed: 11,
+            ..Settings::default()
+        };
+        let mut rng = ChaCha8Rng::seed_from_u64(11);
+        rng.set_stream(2 << 32 | 9);
+        assert_eq!(
+            layer_rng(&settings, 1, 1, Stream::Arpeggio).next_u32(),
+            rng.next_u32(),
+        );
+        assert_ne!(
+            layer_rng(&settings, 1, 1, Stream::Direction).next_u32(),
+            layer_rng(&settings, 1, 1, Stream::Condition).next_u32(),
+        );
+    }
+
+    #[test]
+    fn every_counts_loops() {
+        let settings = Settings::default();
+        let layer = conditional_layer(
+            &settings,
+            &[Condition::Every { nth: 2, of: 3 }, Condition::Always],
+        );
+        let fired = fired(&settings, &layer, 12);
+        let first_steps = fired.iter().step_by(2).copied().collect::<Vec<_>>();
+        assert_eq!(first_steps, [false, true, false, false, true, false]);
+        assert!(fired.iter().skip(1).step_by(2).all(|x| *x));
+    }
+
+    #[test]
+    fn first_only_plays_the_first_loop() {
+        let settings = Settings::default();
+        let layer = conditional_layer(
+            &settings,
+            &[Condition::First, Condition::Always, Condition::Always],
+        );
+        assert_eq!(
+            fired(&settings, &layer, 9),
+            [true, true, true, false, true, true, false, true, true],
+        );
+    }
+
+    #[test]
+    fn not_previous_plays_when_the_last_condition_did_not() {
+        let settings = Settings {
+            seed: 3,
+            ..Settings::default()
+        };
+        let layer = conditional_layer(
+            &settings,
+            &[
+                Condition::Chance { percent: 50 },
+                Condition::Always,
+                Condition::NotPrevious,
+            ],
+        );
+        let fired = fired(&settings, &layer, 300);
+        assert!(fired.chunks(3).all(|steps| steps[0] != steps[2]));
+    }
+
+    #[test]
+    fn not_previous_alternates_across_loops() {
+        let settings = Settings::default();
+        let layer = conditional_layer(
+            &settings,
+            &[
+                Condition::NotPrevious,
+                Condition::NotPrevious,
+                Condition::Always,
+            ],
+        );
+        let fired = fired(&settings, &layer, 12);
+        let conditional = fired
+            .chunks(3)
+            .flat_map(|steps| [steps[0], steps[1]])
+            .collect::<Vec<bool>>();
+        assert!(conditional.windows(2).all(|pair| pair[0] != pair[1]));
+        assert!(conditional[0]);
+    }
+
+    #[test]
+    fn random_directions_stay_on_the_layer() {
+        let settings = Settings::default();
+        let mut layer = conditional_layer(&settings, &[Condition::Always; 5]);
+        layer.direction = Direction::Random;
+        let steps = (0..100)
+            .map(|x| layer_step(&settings, 0, 0, &layer, x))
+            .collect::<Vec<usize>>();
+        assert!(steps.iter().all(|step| *step < 5));
+        assert_eq!(steps, {
+            (0..100)
+                .map(|x| layer_step(&settings, 0, 0, &layer, x))
+                .collect::<Vec<usize>>()
+        });
+    }
+
+    #[test]
+    fn brownian_moves_a_step_at_most_and_starts_over_each_loop() {
+        let settings = Settings::default();
+        let mut layer = conditional_layer(&settings, &[Condition::Always; 5]);
+        layer.direction = Direction::Brownian;
+        let steps = (0..100)
+            .map(|x| layer_step(&settings, 0, 0, &layer, x))
+            .collect::<Vec<usize>>();
+        assert!(steps.iter().step_by(5).all(|step| *step == 0));
+        assert!(steps.chunks(5).all(|walk| {
+            walk.windows(2)
+                .all(|pair| matches!((pair[1] + 5 - pair[0]) % 5, 0 | 1 | 4))
+        }));
+    }
+
+    #[test]
+    fn directions_walk_the_layer_in_order() {
+        let settings = Settings::default();
+        let mut layer = conditional_layer(&settings, &[Condition::Always; 4]);
+        let walk = |layer: &Layer| {
+            (0..8)
+                .map(|x| layer_step(&settings, 0, 0, layer, x))
+                .collect::<Vec<usize>>()
+        };
+        assert_eq!(walk(&layer), vec![0, 1, 2, 3, 0, 1, 2, 3]);
+        layer.direction = Direction::Reverse;
+        assert_eq!(walk(&layer), vec![3, 2, 1, 0, 3, 2, 1, 0]);
+        layer.direction = Direction::PingPong;
+        assert_eq!(walk(&layer), vec![0, 1, 2, 3, 2, 1, 0, 1]);
+    }
+
+    #[test]
+    fn steps_start_on_the_tick_they_are_taken() {
+        let settings = Settings::default();
+        let mut layer = conditional_layer(&settings, &[Condition::Always; 4]);
+        for (multiplier, divider) in [(1, 1), (3, 2), (1, 2), (5, 1), (2, 3)] {
+            layer.rate = ClockRate {
+                multiplier,
+                divider,
+            };
+            assert!((1..20).all(|steps_taken| {
+                let tick = step_start_tick(&layer, steps_taken);
+                layer_steps_taken(&layer, tick) == steps_taken
+                    && layer_steps_taken(&layer, tick - 1) == steps_taken - 1
+            }));
+        }
+        layer.rate = ClockRate {
+            multiplier: 2,
+            divider: 1,
+        };
+        assert_eq!(step_ticks(&layer), TICKS_PER_STEP / 2);
+    }
+
+    #[test]
+    fn arpeggios_go_up_and_down_across_octaves() {
+        let settings = Settings::default();
+        let mut layer = conditional_layer(&settings, &[Condition::Always]);
+        layer.arpeggio.octaves = 2;
+        let note = Note {
+            octaves: vec![67, 60, 64],
+            length: 1,
+            ..Note::default()
+        };
+        let arpeggio = |layer: &Layer| {
+            (0..8)
+                .map(|n| arpeggio_note(&settings, 0, 0, layer, &note, 0, n))
+                .collect::<Option<Vec<(usize, usize)>>>()
+                .unwrap()
+        };
+        let up = [(60, 0), (64, 0), (67, 0), (60, 1), (64, 1), (67, 1)];
+        assert_eq!(arpeggio(&layer)[..6], up);
+        layer.arpeggio.mode = ArpeggioMode::Down;
+        assert_eq!(arpeggio(&layer)[..2], [(67, 1), (64, 1)]);
+        layer.arpeggio.mode = ArpeggioMode::UpDown;
+        assert_eq!(arpeggio(&layer)[5..], [(67, 1), (64, 1), (60, 1)]);
+        layer.arpeggio.mode = ArpeggioMode::AsPlayed;
+        assert_eq!(arpeggio(&layer)[..3], [(67, 0), (60, 0), (64, 0)]);
+    }
+}
@@ -0,0 +1,160 @@
+use crate::error::AppError;
+use crate::follow::follow_layers;
+use crate::state::{GlobalState, Section};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+/**
+ * A Project is what is kept from one session to the next: the pattern and the
+ * dimensions it was built to, where the transposition was left, and the seed
+ * everything random is drawn from. Everything else starts over. Projects are
+ * kept as JSON, and open with their own dimensions whatever the settings they
+ * are opened with.
+ */
+#[derive(Deserialize, Serialize)]
+pub struct Project {
+    pub sections: Vec<Section>,
+    pub transpose: i32,
+    // Kept so a mutation run can be played back the same way, whatever seed
+    // the project is opened with.
+    pub seed: u64,
+    pub section_count: usize,
+    pub layer_count: usize,
+    pub section_length: usize,
+    pub row_count: usize,
+}
+
+impl Project {
+    pub fn of(state: &GlobalState) -> Project {
+        Project {
+            sections: state.sections.clone(),
+            transpose: state.player.transpose,
+            seed: state.settings.seed,
+            section_count: state.settings.section_count,
+            layer_count: state.settings.layer_count,
+            section_length: state.settings.section_length,
+            row_count: state.settings.row_count,
+        }
+    }
+
+    // Whether the state has anything in it the project doesn't.
+    fn is_behind(&self, state: &GlobalState) -> bool {
+        self.transpose != state.player.transpose
+            || self.seed != state.settings.seed
+            || self.section_count != state.settings.section_count
+            || self.layer_count != state.settings.layer_count
+            || self.section_length != state.settings.section_length
+            || self.row_count != state.settings.row_count
+            || self.sections != state.sections
+    }
+
+    pub fn open(self, state: GlobalState) -> GlobalState {
+        let mut new_state = state;
+        new_state.sections = self.sections;
+        new_state.player.transpose = self.transpose;
+        new_state.settings.seed = self.seed;
+        new_state.settings.section_count = self.section_count;
+        new_state.settings.layer_count = self.layer_count;
+        new_state.settings.section_length = self.section_length;
+        new_state.settings.row_count = self.row_count;
+        // In case the project was written by hand.
+        for section in new_state.sections.iter_mut() {
+            follow_layers(&new_state.settings, section);
+        }
+        new_state
+    }
+}
+
+pub fn read_project(path: &Path) -> Result<Project, AppError> {
+    let file = File::open(path).map_err(AppError::ProjectReadError)?;
+    serde_json::from_reader(BufReader::new(file))
+        .map_err(AppError::ProjectParseError)
+}
+
+// Written alongside and moved into place, so a project is never left half
+// written.
+fn write_project(path: &Path, project: &Project) -> Result<(), AppError> {
+    let written = path.with_extension("tmp");
+    let file = File::create(&written).map_err(AppError::ProjectWriteError)?;
+    serde_json::to_writer(file, project)
+        .map_err(AppError::ProjectParseError)?;
+    fs::rename(&written, path).map_err(AppError::ProjectWriteError)
+}
+
+/**
+ * The file a project is saved to. It is written whenever the project changes,
+ * so there is nothing to remember to save before switching off.
+ */
+pub struct ProjectFile {
+    path: PathBuf,
+    saved: Project,
+}
+
+impl ProjectFile {
+    pub fn new(path: &Path, state: &GlobalState) -> ProjectFile {
+        ProjectFile {
+            path: path.to_path_buf(),
+            saved: Project::of(state),
+        }
+    }
+
+    pub fn update(&mut self, state: &GlobalState) -> Result<(), AppError> {
+        if !self.saved.is_behind(state) {
+            return Ok(());
+        }
+        self.saved = Project::of(state);
+        write_project(&self.path, &self.saved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{initial_state, Settings};
+
+    #[test]
+    fn projects_open_as_they_were_saved() {
+        let mut state = initial_state(Settings::default());
+        state.sections[1].layers[2].notes[3].octaves = vec![64];
+        state.player.transpose = -3;
+        state.settings.seed = 7;
+        let json = serde_json::to_string(&Project::of(&state)).unwrap();
+        let project = serde_json::from_str::<Project>(&json).unwrap();
+        let opened = project.open(initial_state(Settings::default()));
+        assert!(opened.sections == state.sections);
+        assert_eq!(opened.player.transpose, -3);
+        assert_eq!(opened.settings.seed, 7);
+    }
+
+    #[test]
+    fn only_changes_to_the_project_are_saved() {
+        let mut state = initial_state(Settings::default());
+        let project = Project::of(&state);
+        state.player.interval = 12;
+        state.player.active_layer_index = 1;
+        assert!(!project.is_behind(&state));
+        state.player.transpose = 2;
+        assert!(project.is_behind(&state));
+    }
+
+    #[test]
+    fn projects_open_with_the_dimensions_they_were_made_with() {
+        let made = Settings {
+            section_count: 2,
+            layer_count: 3,
+            section_length: 32,
+            row_count: 24,
+            ..Settings::default()
+        };
+        let project = Project::of(&initial_state(made.clone()));
+        let opened = project.open(initial_state(Settings::default()));
+        assert!(opened.settings == made);
+        assert_eq!(opened.sections.len(), 2);
+        assert!(opened.sections.iter().all(|section| {
+            section.layers.len() == 3
+                && section.layers.iter().all(|layer| layer.notes.len() == 32)
+        }));
+    }
+}
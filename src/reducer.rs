@@ -1,14 +1,765 @@
-use crate::action::Action;
-use crate::state::{GlobalState, Note};
+use crate::action::{Action, Scope};
+use crate::chord::{memorize, Chord};
+use crate::effects::{is_fill, is_held, played_interval, HeldEffect};
+use crate::euclid::Euclid;
+use crate::follow::follow_layers;
+use crate::grid_keyboard::key_row;
+use crate::layer_settings::LayerSetting;
+use crate::morph::played_section;
+use crate::mutation::{mutate, MAX_MUTATION_EVERY};
+use crate::playback::{
+    count_loops, layer_steps_taken, mutation_rng, nearest_step, playhead_step,
+};
+use crate::state::{
+    Clipboard, ClockRate, GlobalState, HeldKey, Layer, LayerKind, Note,
+    PlayMode, Player, RecordMode, Section, Settings, View, MAX_NUDGE,
+    MAX_RATCHETS, TRANSPOSE_REFERENCE,
+};
+use std::sync::Arc;
 
+fn active_section_mut(state: &mut GlobalState) -> Option<&mut Section> {
+    state.sections.get_mut(state.player.active_section_index)
+}
+
+fn active_layer_mut(state: &mut GlobalState) -> Option<&mut Layer> {
+    let layer_index = state.player.active_layer_index;
+    active_section_mut(state)
+        .and_then(|section| section.layers.get_mut(layer_index))
+}
+
+// The lowest row of the active layer showing on the grid.
+fn active_row_offset(state: &GlobalState) -> usize {
+    let player = &state.player;
+    state
+        .sections
+        .get(player.active_section_index)
+        .and_then(|section| section.layers.get(player.active_layer_index))
+        .map_or(0, |layer| {
+            player.row_offset(
+                &state.settings,
+                player.active_section_index,
+                player.active_layer_index,
+                layer,
+            )
+        })
+}
+
+// Pads only ever know where they are on the grid. This finds the step under
+// one on the current page.
+fn step_index(state: &GlobalState, x: u32) -> usize {
+    state.player.page * state.settings.page_length + x as usize
+}
+
+fn select_page(state: GlobalState, page: usize) -> GlobalState {
+    if page < state.settings.page_count() {
+        let mut new_state = state.clone();
+        new_state.player.page = page;
+        // Picking a page by hand means we want to stay there.
+        new_state.player.follow = false;
+        new_state.player.view = View::Steps;
+        new_state
+    } else {
+        state
+    }
+}
+
+// Notes keep their pitch where the other kind of layer has a row for it, and
+// are dropped otherwise.
+fn toggle_drum_mode(state: GlobalState) -> GlobalState {
+    let mut new_state = state.clone();
+    let settings = state.settings.clone();
+    match active_layer_mut(&mut new_state) {
+        Some(layer) => {
+            let kind = match layer.kind {
+                LayerKind::Melodic
+                | LayerKind::Arpeggio
+                | LayerKind::Follower => LayerKind::Drum {
+                    voices: settings.drum_voices.clone(),
+                },
+                LayerKind::Drum { .. } => LayerKind::Melodic,
+            };
+            let mut new_layer = Layer {
+                kind,
+                ..layer.clone()
+            };
+            let row_count = new_layer.row_count(&settings);
+            let notes = layer
+                .notes
+                .iter()
+                .map(|note| Note {
+                    octaves: note
+                        .octaves
+                        .iter()
+                        .filter_map(|row| layer.pitch(*row))
+                        .filter_map(|pitch| new_layer.row(pitch))
+                        .filter(|row| *row < row_count)
+                        .collect(),
+                    ..note.clone()
+                })
+                .collect();
+            new_layer.notes = notes;
+            *layer = new_layer;
+            // The rows of the other kind are nothing like these, so start
+            // from where a new layer of the kind would.
+            new_state.player.row_offsets.remove(&(
+                state.player.active_section_index,
+                state.player.active_layer_index,
+            ));
+            new_state
+        }
+        None => state,
+    }
+}
+
+// Layers can be cut short, or run on past the rest of the section, with empty
+// steps added as they grow.
+fn change_layer_length(state: GlobalState, pos: u32, x: u32) -> GlobalState {
+    let mut new_state = state.clone();
+    let length = step_index(&state, x) + 1;
+    let changed = active_section_mut(&mut new_state)
+        .and_then(|section| section.layers.get_mut(pos as usize))
+        .filter(|_| length <= state.settings.max_layer_length())
+        .map(|layer| {
+            if layer.notes.len() < length {
+                layer.notes.resize(length, Note::default());
+            }
+            layer.length = length
+        });
+    match changed {
+        Some(()) => new_state,
+        None => state,
+    }
+}
+
+fn change_layer_rate(
+    state: GlobalState,
+    pos: u32,
+    rate: ClockRate,
+) -> GlobalState {
+    let mut new_state = state.clone();
+    let changed = active_section_mut(&mut new_state)
+        .and_then(|section| section.layers.get_mut(pos as usize))
+        .filter(|_| rate.multiplier > 0 && rate.divider > 0)
+        .map(|layer| layer.rate = rate);
+    match changed {
+        Some(()) => new_state,
+        None => state,
+    }
+}
+
+// Change a step on the current page of the active layer. Steps can only be
+// picked out in the views that show them, and each edit belongs to one.
+fn edit_step<F: FnOnce(&mut Note)>(
+    state: GlobalState,
+    view: View,
+    x: u32,
+    edit: F,
+) -> GlobalState {
+    let mut new_state = state.clone();
+    let edited = active_layer_mut(&mut new_state)
+        .filter(|_| state.player.view == view)
+        .and_then(|layer| layer.notes.get_mut(step_index(&state, x)))
+        .map(edit);
+    match edited {
+        Some(()) => new_state,
+        None => state,
+    }
+}
+
+// Lock the controller in the automation view to a value on a step, or unlock
+// it with None. Steps can be locked from any view, so a fader can be ridden
+// along with the pattern wherever it is being looked at.
+fn lock_step(state: GlobalState, x: u32, value: Option<u8>) -> GlobalState {
+    let view = state.player.view.clone();
+    let cc = state
+        .sections
+        .get(state.player.active_section_index)
+        .and_then(|section| section.layers.get(state.player.active_layer_index))
+        .map(|layer| layer.automation_cc);
+    match cc {
+        Some(cc) => edit_step(state, view, x, |note| match value {
+            Some(value) => {
+                note.locks.insert(cc, value);
+            }
+            None => {
+                note.locks.remove(&cc);
+            }
+        }),
+        None => state,
+    }
+}
+
+// Adds the row a pitch is on to a step. Unlike pressing a pad, playing a key
+// that is already there leaves it there.
+fn record_pitch(
+    layer: &mut Layer,
+    settings: &Settings,
+    step: usize,
+    pitch: u8,
+) {
+    let row_count = layer.row_count(settings);
+    let row = layer.row(pitch).filter(|row| *row < row_count);
+    if let Some((row, note)) = row.zip(layer.notes.get_mut(step)) {
+        if !note.octaves.contains(&row) {
+            note.octaves.push(row);
+        }
+        note.length = note.length.max(1);
+    }
+}
+
+fn record_note(state: GlobalState, pitch: u8) -> GlobalState {
+    let mut new_state = state.clone();
+    let settings = state.settings.clone();
+    let player = state.player.clone();
+    match (player.record, active_layer_mut(&mut new_state)) {
+        // There is no playhead to land on unless playing.
+        (RecordMode::RealTime, Some(layer))
+            if matches!(player.play_mode, PlayMode::Playing) =>
+        {
+            // Keys land where they are heard, which a held effect may have
+            // moved away from the clock.
+            let step = nearest_step(
+                &settings,
+                player.active_section_index,
+                player.active_layer_index,
+                layer,
+                played_interval(&player, player.interval),
+            );
+            record_pitch(layer, &settings, step, pitch);
+            new_state
+        }
+        (RecordMode::StepEntry, Some(layer)) => {
+            record_pitch(layer, &settings, player.cursor, pitch);
+            let cursor = (player.cursor + 1) % layer.length.max(1);
+            new_state.player.cursor = cursor;
+            if player.follow {
+                new_state.player.page = cursor / settings.page_length;
+            }
+            new_state
+        }
+        _ => state,
+    }
+}
+
+// The pad sounds the note under it for as long as it is held, and records it
+// too if recording.
+fn press_key(state: GlobalState, x: u32, y: u32) -> GlobalState {
+    let layer_index = state.player.active_layer_index;
+    let pitch = state
+        .sections
+        .get(state.player.active_section_index)
+        .and_then(|section| section.layers.get(layer_index))
+        .and_then(|layer| {
+            let row_offset = active_row_offset(&state);
+            key_row(&state.settings, layer, row_offset, x as usize, y as usize)
+                .and_then(|row| layer.pitch(row))
+        });
+    match pitch {
+        Some(pitch)
+            if !state.player.keys.iter().any(|key| key.pitch == pitch) =>
+        {
+            let mut new_state = state.clone();
+            new_state.player.keys.push(HeldKey {
+                x,
+                y,
+                layer_index,
+                pitch,
+            });
+            record_note(new_state, pitch)
+        }
+        _ => state,
+    }
+}
+
+// The chord of the layer replaces whatever rows the step had, starting from
+// the row under the pad. Rows of the chord past the last row are left out.
+fn stamp_chord(state: GlobalState, x: u32, y: u32) -> GlobalState {
+    let mut new_state = state.clone();
+    let step = step_index(&state, x);
+    let stamped = active_layer_mut(&mut new_state)
+        .filter(|_| state.player.view == View::Steps)
+        .and_then(|layer| {
+            let root = active_row_offset(&state) + y as usize;
+            let row_count = layer.row_count(&state.settings);
+            let rows = layer
+                .chord
+                .intervals(&layer.chord_memory)
+                .iter()
+                .map(|interval| root + interval)
+                .filter(|row| *row < row_count)
+                .collect::<Vec<usize>>();
+            layer.notes.get_mut(step).map(|note| {
+                note.octaves = rows;
+                note.length = note.length.max(1);
+            })
+        });
+    match stamped {
+        Some(()) => new_state,
+        None => state,
+    }
+}
+
+fn memorize_chord(state: GlobalState, x: u32) -> GlobalState {
+    let mut new_state = state.clone();
+    let step = step_index(&state, x);
+    let memorized = active_layer_mut(&mut new_state)
+        .filter(|_| state.player.view == View::Steps)
+        .and_then(|layer| {
+            layer
+                .notes
+                .get(step)
+                .and_then(|note| memorize(&note.octaves))
+                .map(|intervals| {
+                    layer.chord_memory = intervals;
+                    layer.chord = Chord::Custom;
+                })
+        });
+    match memorized {
+        Some(()) => new_state,
+        None => state,
+    }
+}
+
+// Hits of the rhythm put the row on a step and the rest take it off, from the
+// first step of the layer to the last.
+fn fill_euclid(state: GlobalState, row: usize, euclid: Euclid) -> GlobalState {
+    let mut new_state = state.clone();
+    let filled = active_layer_mut(&mut new_state)
+        .filter(|layer| row < layer.row_count(&state.settings))
+        .map(|layer| {
+            let length = layer.length;
+            layer.notes.iter_mut().take(length).enumerate().for_each(
+                |(step, note)| {
+                    if !euclid.hit(step) {
+                        note.octaves.retain(|x| *x != row);
+                    } else if !note.octaves.contains(&row) {
+                        note.octaves.push(row);
+                        note.length = note.length.max(1);
+                    }
+                },
+            )
+        });
+    match filled {
+        Some(()) => new_state,
+        None => state,
+    }
+}
+
+// The first three faders set the hits, steps and rotation of the rhythm, as far
+// as the layer is long, and fill the row again.
+fn change_euclid(state: GlobalState, fader: u32, value: u8) -> GlobalState {
+    let length = state
+        .sections
+        .get(state.player.active_section_index)
+        .and_then(|section| section.layers.get(state.player.active_layer_index))
+        .map_or(1, |layer| layer.length.max(1));
+    let scale = |x: usize| value as usize * x / 127;
+    let mut euclid = state.player.euclid;
+    match fader {
+        0 => euclid.hits = scale(length),
+        1 => euclid.steps = 1 + scale(length - 1),
+        2 => euclid.rotation = scale(length - 1),
+        _ => return state,
+    }
+    let mut new_state = state.clone();
+    new_state.player.euclid = euclid;
+    match state.player.euclid_row {
+        Some(row) => fill_euclid(new_state, row, euclid),
+        None => new_state,
+    }
+}
+
+// The first faders set how often the active layer mutates, then how likely
+// each kind of mutation is.
+fn change_mutation(state: GlobalState, fader: u32, value: u8) -> GlobalState {
+    let percent = value as usize * 100 / 127;
+    let mut new_state = state.clone();
+    let changed = active_layer_mut(&mut new_state).and_then(|layer| {
+        let mutation = &mut layer.mutation;
+        match fader {
+            0 => {
+                mutation.every =
+                    1 + value as usize * (MAX_MUTATION_EVERY - 1) / 127
+            }
+            1 => mutation.add = percent,
+            2 => mutation.remove = percent,
+            3 => mutation.shift = percent,
+            4 => mutation.pitch = percent,
+            _ => return None,
+        }
+        Some(())
+    });
+    match changed {
+        Some(()) => new_state,
+        None => state,
+    }
+}
+
+// Layers of the active section that have just come back around to their first
+// step mutate, every so many loops.
+fn mutate_layers(state: &mut GlobalState, before: &Player) {
+    let settings = state.settings.clone();
+    let section_index = state.player.active_section_index;
+    let loops = state.player.loops.clone();
+    if let Some(section) = state.sections.get_mut(section_index) {
+        section.layers.iter_mut().zip(loops).enumerate().for_each(
+            |(layer_index, (layer, loops))| {
+                let looped =
+                    loops.count > before.layer_loops(layer_index).count;
+                if looped
+                    && layer.mutation.is_active()
+                    && loops.count.is_multiple_of(layer.mutation.every.max(1))
+                {
+                    let mut rng = mutation_rng(
+                        &settings,
+                        section_index,
+                        layer_index,
+                        loops.count,
+                    );
+                    mutate(layer, &settings, &mut rng);
+                }
+            },
+        );
+        follow_layers(&settings, section);
+    }
+}
+
+// Count the loops of each layer of the active section, as it plays them.
+fn count_layer_loops(state: &mut GlobalState) {
+    let section_index = state.player.active_section_index;
+    let tick = state.player.interval;
+    let loops =
+        played_section(state, section_index).map_or(vec![], |section| {
+            section
+                .layers
+                .iter()
+                .enumerate()
+                .map(|(layer_index, layer)| {
+                    count_loops(
+                        &state.settings,
+                        is_fill(&state.player),
+                        section_index,
+                        layer_index,
+                        layer,
+                        &state.player.layer_loops(layer_index),
+                        layer_steps_taken(layer, tick),
+                    )
+                })
+                .collect()
+        });
+    state.player.loops = loops;
+}
+
+// Start the active section from the top. Effects held down carry on from there
+// as if they had just been pressed.
+fn restart(state: &mut GlobalState) {
+    state.player.interval = 0;
+    state.player.loops.clear();
+    state
+        .player
+        .effects
+        .iter_mut()
+        .for_each(|held| held.interval = 0);
+}
+
+fn clear_layer(layer: &mut Layer) {
+    layer
+        .notes
+        .iter_mut()
+        .for_each(|note| *note = Note::default());
+}
+
+fn clear(state: GlobalState, scope: Scope) -> GlobalState {
+    let mut new_state = state.clone();
+    let cleared = match scope {
+        Scope::Step { .. } if state.player.view != View::Steps => None,
+        Scope::Step { x } => active_layer_mut(&mut new_state)
+            .and_then(|layer| layer.notes.get_mut(step_index(&state, x)))
+            .map(|note| *note = Note::default()),
+        Scope::Layer { pos } => active_section_mut(&mut new_state)
+            .and_then(|section| section.layers.get_mut(pos as usize))
+            .map(clear_layer),
+        Scope::Section { pos } => new_state
+            .sections
+            .get_mut(pos as usize)
+            .map(|section| section.layers.iter_mut().for_each(clear_layer)),
+    };
+    match cleared {
+        Some(()) => new_state,
+        None => state,
+    }
+}
+
+// Copying only makes sense between things of the same kind. Anything else is
+// left alone.
+fn duplicate(state: GlobalState, from: Scope, to: Scope) -> GlobalState {
+    let mut new_state = state.clone();
+    let copied = match (from, to) {
+        (Scope::Step { .. }, _) if state.player.view != View::Steps => None,
+        (Scope::Step { x: from }, Scope::Step { x: to }) => {
+            let (from, to) = (step_index(&state, from), step_index(&state, to));
+            active_layer_mut(&mut new_state).and_then(|layer| {
+                let note = layer.notes.get(from)?.clone();
+                layer.notes.get_mut(to).map(|x| *x = note)
+            })
+        }
+        (Scope::Layer { pos: from }, Scope::Layer { pos: to }) => {
+            active_section_mut(&mut new_state).and_then(|section| {
+                let layer = section.layers.get(from as usize)?.clone();
+                section.layers.get_mut(to as usize).map(|x| *x = layer)
+            })
+        }
+        (Scope::Section { pos: from }, Scope::Section { pos: to }) => {
+            let section = new_state.sections.get(from as usize).cloned();
+            section.and_then(|section| {
+                new_state
+                    .sections
+                    .get_mut(to as usize)
+                    .map(|x| *x = section)
+            })
+        }
+        _ => None,
+    };
+    match copied {
+        Some(()) => new_state,
+        None => state,
+    }
+}
+
+fn copied(state: &GlobalState, scope: Scope) -> Option<Clipboard> {
+    let section = state.sections.get(state.player.active_section_index)?;
+    match scope {
+        Scope::Step { .. } if state.player.view != View::Steps => None,
+        Scope::Step { x } => section
+            .layers
+            .get(state.player.active_layer_index)?
+            .notes
+            .get(step_index(state, x))
+            .cloned()
+            .map(Clipboard::Step),
+        Scope::Layer { pos } => section
+            .layers
+            .get(pos as usize)
+            .cloned()
+            .map(Clipboard::Layer),
+        Scope::Section { pos } => state
+            .sections
+            .get(pos as usize)
+            .cloned()
+            .map(Clipboard::Section),
+    }
+}
+
+fn copy(state: GlobalState, scope: Scope) -> GlobalState {
+    match copied(&state, scope) {
+        Some(clipboard) => {
+            let mut new_state = state.clone();
+            new_state.player.clipboard = Some(Arc::new(clipboard));
+            new_state
+        }
+        None => state,
+    }
+}
+
+// As with copying from one thing to another, only something of the kind on
+// the clipboard can be pasted over.
+fn paste(state: GlobalState, scope: Scope) -> GlobalState {
+    let mut new_state = state.clone();
+    let pasted = match (state.player.clipboard.as_deref(), scope) {
+        (_, Scope::Step { .. }) if state.player.view != View::Steps => None,
+        (Some(Clipboard::Step(note)), Scope::Step { x }) => {
+            let step = step_index(&state, x);
+            active_layer_mut(&mut new_state)
+                .and_then(|layer| layer.notes.get_mut(step))
+                .map(|x| *x = note.clone())
+        }
+        (Some(Clipboard::Layer(layer)), Scope::Layer { pos }) => {
+            active_section_mut(&mut new_state)
+                .and_then(|section| section.layers.get_mut(pos as usize))
+                .map(|x| *x = layer.clone())
+        }
+        (Some(Clipboard::Section(section)), Scope::Section { pos }) => {
+            new_state
+                .sections
+                .get_mut(pos as usize)
+                .map(|x| *x = section.clone())
+        }
+        _ => None,
+    };
+    match pasted {
+        Some(()) => new_state,
+        None => state,
+    }
+}
+
+// Followers are filled in again after anything that might have changed what
+// they follow. Ticks only do so when a layer mutates, which sees to it itself.
 pub fn reducer(state: GlobalState, action: Action) -> GlobalState {
+    let follows = !matches!(action, Action::Noop | Action::TimeInterval);
+    let mut new_state = reduce(state, action);
+    if follows {
+        for section in new_state.sections.iter_mut() {
+            follow_layers(&new_state.settings, section);
+        }
+    }
+    new_state
+}
+
+fn reduce(state: GlobalState, action: Action) -> GlobalState {
     match action {
         Action::Noop => state,
+        Action::AutomationLaneChange { cc } => {
+            let mut new_state = state.clone();
+            match active_layer_mut(&mut new_state).filter(|_| cc < 128) {
+                Some(layer) => {
+                    layer.automation_cc = cc;
+                    new_state
+                }
+                None => state,
+            }
+        }
+        Action::ChordMemorize { x } => memorize_chord(state, x),
+        Action::ChordStamp { x, y } => stamp_chord(state, x, y),
+        Action::Clear(scope) => clear(state, scope),
+        Action::Copy(scope) => copy(state, scope),
+        Action::Duplicate { from, to } => duplicate(state, from, to),
+        Action::DirectionCycle => {
+            let mut new_state = state.clone();
+            match active_layer_mut(&mut new_state) {
+                Some(layer) => {
+                    layer.direction = layer.direction.next();
+                    new_state
+                }
+                None => state,
+            }
+        }
+        Action::ArpeggioToggle => {
+            let mut new_state = state.clone();
+            // Arpeggio layers have the same rows as melodic ones, so there is
+            // nothing to convert. Drum layers have no chords to arpeggiate.
+            let kind = active_layer_mut(&mut new_state).and_then(|layer| {
+                match layer.kind {
+                    LayerKind::Melodic => Some(LayerKind::Arpeggio),
+                    LayerKind::Arpeggio => Some(LayerKind::Melodic),
+                    LayerKind::Drum { .. } | LayerKind::Follower => None,
+                }
+                .map(|kind| layer.kind = kind)
+            });
+            match kind {
+                Some(()) => new_state,
+                None => state,
+            }
+        }
+        Action::DrumModeToggle => toggle_drum_mode(state),
+        Action::EffectPress(effect) if is_held(&state.player, effect) => state,
+        Action::EffectPress(effect) => {
+            let mut new_state = state.clone();
+            new_state.player.effects.push(HeldEffect {
+                effect,
+                interval: state.player.interval,
+            });
+            new_state
+        }
+        Action::EffectRelease(effect) => {
+            let mut new_state = state.clone();
+            new_state
+                .player
+                .effects
+                .retain(|held| held.effect != effect);
+            new_state
+        }
+        Action::EuclidFill { row, euclid } => fill_euclid(state, row, euclid),
+        Action::FillToggle => {
+            let mut new_state = state.clone();
+            new_state.player.fill = !state.player.fill;
+            new_state
+        }
+        Action::FollowToggle => {
+            let mut new_state = state.clone();
+            new_state.player.follow = !state.player.follow;
+            new_state
+        }
+        Action::LayerLengthChange { pos, x } => {
+            change_layer_length(state, pos, x)
+        }
+        Action::LayerRateChange { pos, rate } => {
+            change_layer_rate(state, pos, rate)
+        }
+        Action::LayerSelect { pos }
+            if pos as usize >= state.settings.layer_count =>
+        {
+            state
+        }
         Action::LayerSelect { pos } => {
             let mut new_state = state.clone();
             new_state.player.active_layer_index = pos as usize;
             new_state
         }
+        // Pads let go of after the grid became a keyboard were pressed before
+        // it was one.
+        Action::GridToggle { .. } if state.player.view == View::Keyboard => {
+            state
+        }
+        // The morph view shows steps as they play, which aren't all there to
+        // be edited.
+        Action::GridToggle { .. } if state.player.view == View::Morph => state,
+        Action::GridToggle { x, y }
+            if state.player.view == View::LayerSettings
+                || state.player.view == View::Follow =>
+        {
+            let mut new_state = state.clone();
+            let chosen = LayerSetting::from_row(
+                &state.settings,
+                &state.player.view,
+                y as usize,
+            )
+            .and_then(|setting| {
+                active_layer_mut(&mut new_state)
+                    .and_then(|layer| setting.choose(layer, x as usize))
+            });
+            match chosen {
+                Some(()) => new_state,
+                None => state,
+            }
+        }
+        // Pressing the top of a column again unlocks the step.
+        Action::GridToggle { x, y }
+            if state.player.view == View::Automation =>
+        {
+            let value = state.settings.lock_value(y as usize);
+            let locked = state
+                .sections
+                .get(state.player.active_section_index)
+                .and_then(|section| {
+                    section.layers.get(state.player.active_layer_index)
+                })
+                .and_then(|layer| {
+                    layer
+                        .notes
+                        .get(step_index(&state, x))
+                        .and_then(|note| note.locks.get(&layer.automation_cc))
+                })
+                .copied();
+            if locked == Some(value) {
+                lock_step(state, x, None)
+            } else {
+                lock_step(state, x, Some(value))
+            }
+        }
+        // Any pad on a row picks it to fill.
+        Action::GridToggle { y, .. } if state.player.view == View::Euclid => {
+            let row = active_row_offset(&state) + y as usize;
+            let mut new_state = state.clone();
+            new_state.player.euclid_row = Some(row);
+            fill_euclid(new_state, row, state.player.euclid)
+        }
+        // In the overview each pad is a page.
+        Action::GridToggle { x, y }
+            if state.player.view == View::PageOverview =>
+        {
+            let page = x as usize + y as usize * state.settings.page_length;
+            select_page(state, page)
+        }
         Action::GridToggle { x, y } => {
             let mut new_state = state.clone();
             let layer_opt = new_state
@@ -16,27 +767,33 @@ pub fn reducer(state: GlobalState, action: Action) -> GlobalState {
                 .get_mut(state.player.active_section_index)
                 .and_then(|section| {
                     section.layers.get_mut(state.player.active_layer_index)
-                });
+                })
+                // Followers have no steps of their own to edit.
+                .filter(|layer| layer.kind != LayerKind::Follower);
             match layer_opt {
                 Some(layer) => {
-                    if let Some(note) = layer.notes.get_mut(x as usize) {
-                        let new_octaves =
-                            if note.octaves.contains(&(y as usize)) {
-                                note.octaves
-                                    .iter()
-                                    .filter(|a| **a as u32 != y)
-                                    // TODO: Kill myself for doing this.
-                                    .map(|a| a.clone()) // Ugh. Whhhhhyy?
-                                    .collect::<Vec<usize>>()
-                            } else {
-                                let mut octaves: Vec<usize> =
-                                    note.octaves.to_vec();
-                                octaves.push(y as usize);
-                                octaves
-                            };
+                    let step = step_index(&state, x);
+                    let row = active_row_offset(&state) + y as usize;
+                    let row_count = layer.row_count(&state.settings);
+                    let note_opt =
+                        layer.notes.get_mut(step).filter(|_| row < row_count);
+                    if let Some(note) = note_opt {
+                        let new_octaves = if note.octaves.contains(&row) {
+                            note.octaves
+                                .iter()
+                                .filter(|a| **a != row)
+                                // TODO: Kill myself for doing this.
+                                .map(|a| a.clone()) // Ugh. Whhhhhyy?
+                                .collect::<Vec<usize>>()
+                        } else {
+                            let mut octaves: Vec<usize> = note.octaves.to_vec();
+                            octaves.push(row);
+                            octaves
+                        };
                         *note = Note {
                             length: 1,
                             octaves: new_octaves,
+                            ..note.clone()
                         };
                         new_state
                     } else {
@@ -46,21 +803,412 @@ pub fn reducer(state: GlobalState, action: Action) -> GlobalState {
                 None => state,
             }
         }
+        Action::KeyPress { x, y } if state.player.view == View::Keyboard => {
+            press_key(state, x, y)
+        }
+        Action::KeyPress { .. } => state,
+        Action::KeyRelease { x, y } => {
+            let mut new_state = state.clone();
+            new_state.player.keys.retain(|key| key.x != x || key.y != y);
+            new_state
+        }
+        Action::LockChange { x, value }
+            if state.player.view == View::Mutation =>
+        {
+            change_mutation(state, x, value.min(127))
+        }
+        Action::LockChange { x: 0, value }
+            if state.player.view == View::Morph =>
+        {
+            let mut new_state = state.clone();
+            new_state.player.morph.amount = value.min(127) as usize * 100 / 127;
+            new_state
+        }
+        Action::LockChange { .. } if state.player.view == View::Morph => state,
+        Action::LockChange { x, value }
+            if state.player.view == View::Euclid =>
+        {
+            change_euclid(state, x, value.min(127))
+        }
+        Action::LockChange { x, value } => {
+            lock_step(state, x, Some(value.min(127)))
+        }
+        Action::KeyboardNote { pitch } if state.player.transpose_input => {
+            let mut new_state = state.clone();
+            new_state.player.transpose =
+                pitch as i32 - TRANSPOSE_REFERENCE as i32;
+            new_state
+        }
+        Action::KeyboardNote { pitch } => record_note(state, pitch),
+        Action::MutationFreezeToggle => {
+            let mut new_state = state.clone();
+            match active_layer_mut(&mut new_state) {
+                Some(layer) => {
+                    layer.mutation.frozen = !layer.mutation.frozen;
+                    new_state
+                }
+                None => state,
+            }
+        }
+        Action::PageSelect { pos } => select_page(state, pos as usize),
+        Action::Paste(scope) => paste(state, scope),
         Action::PlayModeChange(play_mode) => {
             let mut new_state = state.clone();
-            new_state.player = state.player.clone();
+            // Stopping goes back to the top, and so does playing from there.
+            // Playing picks up where a pause left off, and carries on if
+            // already playing.
+            match (&state.player.play_mode, &play_mode) {
+                (_, PlayMode::Stopped)
+                | (PlayMode::Stopped, PlayMode::Playing) => {
+                    restart(&mut new_state)
+                }
+                _ => {}
+            }
             new_state.player.play_mode = play_mode;
             new_state
         }
+        Action::RecordModeCycle => {
+            let mut new_state = state.clone();
+            new_state.player.record = state.player.record.next();
+            // Step entry starts from the first step on the grid.
+            new_state.player.cursor =
+                state.player.page * state.settings.page_length;
+            new_state
+        }
+        // Handled by the history reducer, which wraps this one.
+        Action::Redo | Action::Undo => state,
+        Action::RowScroll { rows } => {
+            let player = &state.player;
+            let max_row_offset = state
+                .sections
+                .get(player.active_section_index)
+                .and_then(|section| {
+                    section.layers.get(player.active_layer_index)
+                })
+                .map(|layer| layer.max_row_offset(&state.settings));
+            match max_row_offset {
+                Some(max_row_offset) => {
+                    let mut new_state = state.clone();
+                    new_state.player.row_offsets.insert(
+                        (
+                            player.active_section_index,
+                            player.active_layer_index,
+                        ),
+                        active_row_offset(&state)
+                            .saturating_add_signed(rows as isize)
+                            .min(max_row_offset),
+                    );
+                    new_state
+                }
+                None => state,
+            }
+        }
+        Action::SectionSelect { pos }
+            if pos as usize >= state.settings.section_count =>
+        {
+            state
+        }
+        // In the morph view sections are picked to morph towards instead.
+        // Picking the same one again, or the active section, stops morphing.
+        Action::SectionSelect { pos } if state.player.view == View::Morph => {
+            let mut new_state = state.clone();
+            let pos = pos as usize;
+            new_state.player.morph.section = if state.player.morph.section
+                == Some(pos)
+                || state.player.active_section_index == pos
+            {
+                None
+            } else {
+                Some(pos)
+            };
+            new_state
+        }
+        // Only the active section plays, so picking another one starts it
+        // from the top.
+        Action::SectionSelect { pos }
+            if pos as usize == state.player.active_section_index =>
+        {
+            state
+        }
         Action::SectionSelect { pos } => {
             let mut new_state = state.clone();
             new_state.player.active_section_index = pos as usize;
+            restart(&mut new_state);
+            new_state
+        }
+        Action::StepConditionCycle { x, kind } => {
+            edit_step(state, View::Steps, x, |note| {
+                note.condition = note.condition.next_of_kind(kind)
+            })
+        }
+        Action::StepNudge { x, amount } => {
+            edit_step(state, View::Steps, x, |note| {
+                note.nudge = (note.nudge + amount).clamp(-MAX_NUDGE, MAX_NUDGE)
+            })
+        }
+        Action::StepRatchet { x, ratchets } => {
+            edit_step(state, View::Steps, x, |note| {
+                note.ratchets = (ratchets as usize).clamp(1, MAX_RATCHETS)
+            })
+        }
+        Action::TapTempo { ms } => {
+            let mut new_state = state.clone();
+            new_state.player.tempo =
+                state.player.tempo.tap(ms, state.player.clock);
             new_state
         }
+        // The clock ticks whether or not anything is playing. Playback only
+        // moves along while playing.
         Action::TimeInterval => {
             let mut new_state = state.clone();
-            new_state.player.interval = state.player.interval + 1;
+            new_state.player.clock = state.player.clock + 1;
+            if let PlayMode::Playing = state.player.play_mode {
+                new_state.player.interval = state.player.interval + 1;
+                count_layer_loops(&mut new_state);
+                mutate_layers(&mut new_state, &state.player);
+                // Follow the playhead of whichever layer is on the grid, since
+                // every layer can be somewhere different.
+                let step = state
+                    .sections
+                    .get(state.player.active_section_index)
+                    .and_then(|section| {
+                        section.layers.get(state.player.active_layer_index)
+                    })
+                    .map(|layer| {
+                        playhead_step(
+                            &state.settings,
+                            state.player.active_section_index,
+                            state.player.active_layer_index,
+                            layer,
+                            new_state.player.interval,
+                        )
+                    });
+                // Step entry keeps the page on the cursor instead.
+                let follow = state.player.follow
+                    && state.player.record != RecordMode::StepEntry;
+                if let Some(step) = step.filter(|_| follow) {
+                    new_state.player.page = step / state.settings.page_length;
+                }
+            }
             new_state
         }
+        Action::TransposeInputToggle => {
+            let mut new_state = state.clone();
+            new_state.player.transpose_input = !state.player.transpose_input;
+            new_state
+        }
+        Action::ViewToggle(view) => {
+            let mut new_state = state.clone();
+            new_state.player.view = if state.player.view == view {
+                View::Steps
+            } else {
+                view
+            };
+            // Keys can't be let go of once the grid stops being a keyboard.
+            if new_state.player.view != View::Keyboard {
+                new_state.player.keys.clear();
+            }
+            new_state
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::Effect;
+    use crate::playback::TICKS_PER_STEP;
+    use crate::state::{initial_state, DrumVoice};
+
+    fn dispatch(state: GlobalState, actions: Vec<Action>) -> GlobalState {
+        actions.into_iter().fold(state, reducer)
+    }
+
+    fn ticks(state: GlobalState, count: usize) -> GlobalState {
+        (0..count).fold(state, |state, _| reducer(state, Action::TimeInterval))
+    }
+
+    #[test]
+    fn layers_mutate_each_time_they_loop() {
+        let mut state = dispatch(
+            initial_state(Settings::default()),
+            vec![
+                Action::GridToggle { x: 0, y: 0 },
+                Action::PlayModeChange(PlayMode::Playing),
+            ],
+        );
+        state.sections[0].layers[0].mutation.add = 100;
+        let length = state.sections[0].layers[0].length;
+        let state = ticks(state, length * TICKS_PER_STEP - 1);
+        let filled = |state: &GlobalState| {
+            state.sections[0].layers[0]
+                .notes
+                .iter()
+                .filter(|note| !note.octaves.is_empty())
+                .count()
+        };
+        assert_eq!(filled(&state), 1);
+        let state = ticks(state, 1);
+        assert_eq!(state.player.layer_loops(0).count, 1);
+        assert_eq!(filled(&state), length);
+    }
+
+    #[test]
+    fn layers_only_mutate_while_playing() {
+        let mut state = dispatch(
+            initial_state(Settings::default()),
+            vec![Action::GridToggle { x: 0, y: 0 }],
+        );
+        state.sections[0].layers[0].mutation.add = 100;
+        let notes = state.sections[0].layers[0].notes.clone();
+        let state = ticks(state, 100 * TICKS_PER_STEP);
+        assert_eq!(state.player.interval, 0);
+        assert_eq!(state.player.clock, 100 * TICKS_PER_STEP);
+        assert_eq!(state.sections[0].layers[0].notes, notes);
+    }
+
+    #[test]
+    fn layers_paste_into_other_sections() {
+        let state = dispatch(
+            initial_state(Settings::default()),
+            vec![
+                Action::GridToggle { x: 0, y: 0 },
+                Action::Copy(Scope::Layer { pos: 0 }),
+                Action::SectionSelect { pos: 1 },
+                Action::Paste(Scope::Layer { pos: 2 }),
+            ],
+        );
+        assert!(state.sections[1].layers[2] == state.sections[0].layers[0]);
+        assert!(state.sections[1].layers[0] != state.sections[0].layers[0]);
+    }
+
+    #[test]
+    fn only_the_kind_copied_can_be_pasted() {
+        let state = dispatch(
+            initial_state(Settings::default()),
+            vec![
+                Action::GridToggle { x: 0, y: 0 },
+                Action::Copy(Scope::Step { x: 0 }),
+                Action::Paste(Scope::Layer { pos: 1 }),
+                Action::Paste(Scope::Step { x: 3 }),
+            ],
+        );
+        let layers = &state.sections[0].layers;
+        assert!(
+            layers[1]
+                == initial_state(Settings::default()).sections[0].layers[1]
+        );
+        assert!(layers[0].notes[3] == layers[0].notes[0]);
+    }
+
+    #[test]
+    fn drum_layers_take_the_voices_of_the_drum_map() {
+        let voices = vec![DrumVoice {
+            name: "Kick".to_string(),
+            pitch: 35,
+            color: 0xff0000,
+        }];
+        let settings = Settings {
+            drum_voices: voices.clone(),
+            ..Settings::default()
+        };
+        let state =
+            dispatch(initial_state(settings), vec![Action::DrumModeToggle]);
+        let layer = &state.sections[0].layers[0];
+        assert_eq!(layer.kind, LayerKind::Drum { voices });
+        assert_eq!(layer.pitch(0), Some(35));
+    }
+
+    #[test]
+    fn layers_grow_past_the_section() {
+        let state = dispatch(
+            initial_state(Settings::default()),
+            vec![
+                Action::PageSelect { pos: 2 },
+                Action::LayerLengthChange { pos: 0, x: 3 },
+            ],
+        );
+        let layer = &state.sections[0].layers[0];
+        let length = 2 * state.settings.page_length + 4;
+        assert_eq!(layer.length, length);
+        assert_eq!(layer.notes.len(), length);
+        let state = dispatch(
+            state,
+            vec![
+                Action::PageSelect { pos: 0 },
+                Action::LayerLengthChange { pos: 0, x: 1 },
+            ],
+        );
+        let layer = &state.sections[0].layers[0];
+        assert_eq!(layer.length, 2);
+        assert_eq!(layer.notes.len(), length);
+    }
+
+    #[test]
+    fn keys_are_only_recorded_in_real_time_while_playing() {
+        let state = dispatch(
+            initial_state(Settings::default()),
+            vec![Action::RecordModeCycle],
+        );
+        let pitch = state.sections[0].layers[0].pitch(0).unwrap();
+        let filled = |state: &GlobalState| {
+            state.sections[0].layers[0]
+                .notes
+                .iter()
+                .filter(|note| !note.octaves.is_empty())
+                .count()
+        };
+        let state = dispatch(state, vec![Action::KeyboardNote { pitch }]);
+        assert_eq!(filled(&state), 0);
+        let state = dispatch(
+            state,
+            vec![
+                Action::PlayModeChange(PlayMode::Playing),
+                Action::KeyboardNote { pitch },
+            ],
+        );
+        assert_eq!(filled(&state), 1);
+    }
+
+    #[test]
+    fn keys_are_recorded_where_they_are_heard() {
+        let state = dispatch(
+            initial_state(Settings::default()),
+            vec![
+                Action::RecordModeCycle,
+                Action::PlayModeChange(PlayMode::Playing),
+            ],
+        );
+        let state = ticks(state, 2 * TICKS_PER_STEP);
+        let state =
+            dispatch(state, vec![Action::EffectPress(Effect::HalfTime)]);
+        let state = ticks(state, 2 * TICKS_PER_STEP);
+        let pitch = state.sections[0].layers[0].pitch(0).unwrap();
+        let state = dispatch(state, vec![Action::KeyboardNote { pitch }]);
+        let notes = &state.sections[0].layers[0].notes;
+        assert!(!notes[3].octaves.is_empty());
+        assert!(notes[4].octaves.is_empty());
+    }
+
+    #[test]
+    fn faders_lock_steps_from_the_steps_view() {
+        let state = dispatch(
+            initial_state(Settings::default()),
+            vec![
+                Action::AutomationLaneChange { cc: 7 },
+                Action::LockChange { x: 2, value: 90 },
+            ],
+        );
+        let notes = &state.sections[0].layers[0].notes;
+        assert_eq!(notes[2].locks.get(&7), Some(&90));
+    }
+
+    #[test]
+    fn chords_are_stamped_up_from_the_row_pressed() {
+        let state = initial_state(Settings::default());
+        let root = active_row_offset(&state) + 2;
+        let state = dispatch(state, vec![Action::ChordStamp { x: 1, y: 2 }]);
+        let note = &state.sections[0].layers[0].notes[1];
+        assert_eq!(note.octaves, vec![root, root + 4, root + 7]);
     }
 }
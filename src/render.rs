@@ -0,0 +1,650 @@
+use crate::{
+    device::{Color, ColorStyle, Device, MidiOutput},
+    effects::{is_fill, played_interval},
+    error::AppError,
+    grid_keyboard::key_row,
+    layer_settings::LayerSetting,
+    morph::played_section,
+    playback::{arpeggio_position, playhead_step},
+    state::{
+        GlobalState, Layer, LayerKind, Note, PlayMode, Player, RecordMode,
+        Section, Settings, View,
+    },
+};
+use std::borrow::Cow;
+
+/**
+ * The layer whose steps are on the grid, and what drawing them goes by, worked
+ * out once for the whole layer.
+ */
+struct ShownSteps<'a> {
+    settings: &'a Settings,
+    layer_index: usize,
+    layer: &'a Layer,
+    // The step lit up as the one playing, if any.
+    playhead: Option<usize>,
+    // The step and row an arpeggio is on, if it is on one.
+    arpeggio: Option<(usize, usize)>,
+    row_offset: usize,
+    // Whether the layer has notes above and below the rows on the grid.
+    beyond: (bool, bool),
+}
+
+fn note_to_device(
+    device: &dyn Device,
+    output: &dyn MidiOutput,
+    shown: &ShownSteps,
+    x: usize,
+    note_interval: usize,
+    note: &Note,
+) -> Result<(), AppError> {
+    (0..device.grid_height()).try_for_each(|note_octave| {
+        let edge = match shown.beyond {
+            (true, _) if note_octave + 1 == device.grid_height() => true,
+            (_, true) if note_octave == 0 => true,
+            _ => false,
+        };
+        (x..device.grid_width()).try_for_each(|note_interval_by_length| {
+            device.set_grid_button(
+                output,
+                x,
+                note_octave,
+                note_color(
+                    shown,
+                    note_interval,
+                    note,
+                    shown.row_offset + note_octave,
+                    note_interval_by_length,
+                    edge,
+                ),
+            )
+        })
+    })
+}
+
+// For columns past the end of the layer.
+fn column_off(
+    device: &dyn Device,
+    output: &dyn MidiOutput,
+    x: usize,
+) -> Result<(), AppError> {
+    (0..device.grid_height())
+        .try_for_each(|y| device.set_grid_button(output, x, y, off_color()))
+}
+
+// The steps on the current page.
+fn steps_to_device(
+    device: &dyn Device,
+    output: &dyn MidiOutput,
+    settings: &Settings,
+    player: &Player,
+    section_index: usize,
+    layer_index: usize,
+    layer: &Layer,
+) -> Result<(), AppError> {
+    let page_start = player.page * settings.page_length;
+    // In step entry the cursor stands in for the playhead.
+    let playhead = match player.record {
+        RecordMode::StepEntry => Some(player.cursor),
+        RecordMode::Off | RecordMode::RealTime => Some(playhead_step(
+            settings,
+            section_index,
+            layer_index,
+            layer,
+            played_interval(player, player.interval),
+        )),
+    };
+    // Arpeggios light up the note they are on, wherever the chord started.
+    let arpeggio = match layer.kind {
+        LayerKind::Arpeggio => arpeggio_position(
+            settings,
+            is_fill(player),
+            section_index,
+            layer_index,
+            layer,
+            &player.layer_loops(layer_index),
+            played_interval(player, player.interval),
+        ),
+        LayerKind::Melodic | LayerKind::Drum { .. } | LayerKind::Follower => {
+            None
+        }
+    };
+    let row_offset =
+        player.row_offset(settings, section_index, layer_index, layer);
+    let rows = || layer.notes.iter().flat_map(|note| &note.octaves);
+    let shown = ShownSteps {
+        settings,
+        layer_index,
+        layer,
+        playhead,
+        arpeggio,
+        row_offset,
+        beyond: (
+            rows().any(|row| *row >= row_offset + settings.visible_rows),
+            rows().any(|row| *row < row_offset),
+        ),
+    };
+    (0..device.grid_width()).try_for_each(|x| {
+        match layer.notes.get(page_start + x) {
+            Some(note) if page_start + x < layer.length => {
+                note_to_device(device, output, &shown, x, page_start + x, note)
+            }
+            _ => column_off(device, output, x),
+        }
+    })
+}
+
+// Every page of the layer, one per pad.
+fn overview_to_device(
+    device: &dyn Device,
+    output: &dyn MidiOutput,
+    settings: &Settings,
+    player: &Player,
+    layer_index: usize,
+    layer: &Layer,
+) -> Result<(), AppError> {
+    (0..device.grid_height()).try_for_each(|y| {
+        (0..device.grid_width()).try_for_each(|x| {
+            device.set_grid_button(
+                output,
+                x,
+                y,
+                page_color(
+                    settings,
+                    layer_index,
+                    layer,
+                    player.page,
+                    x + y * settings.page_length,
+                ),
+            )
+        })
+    })
+}
+
+// The controller in the automation lane of the layer, as a column per step.
+fn automation_to_device(
+    device: &dyn Device,
+    output: &dyn MidiOutput,
+    settings: &Settings,
+    player: &Player,
+    layer_index: usize,
+    layer: &Layer,
+) -> Result<(), AppError> {
+    let page_start = player.page * settings.page_length;
+    (0..device.grid_height()).try_for_each(|y| {
+        (0..device.grid_width()).try_for_each(|x| {
+            let height = layer
+                .notes
+                .get(page_start + x)
+                .filter(|_| page_start + x < layer.length)
+                .and_then(|note| note.locks.get(&layer.automation_cc))
+                .map_or(0, |value| settings.lock_height(*value));
+            device.set_grid_button(
+                output,
+                x,
+                y,
+                if y < height {
+                    Color {
+                        rgb: layer_color(layer_index),
+                        style: ColorStyle::Steady75,
+                    }
+                } else {
+                    off_color()
+                },
+            )
+        })
+    })
+}
+
+// The settings of the layer, one per row.
+fn layer_settings_to_device(
+    device: &dyn Device,
+    output: &dyn MidiOutput,
+    settings: &Settings,
+    view: &View,
+    layer_index: usize,
+    layer: &Layer,
+) -> Result<(), AppError> {
+    (0..device.grid_height()).try_for_each(|y| {
+        let setting = LayerSetting::from_row(settings, view, y);
+        (0..device.grid_width()).try_for_each(|x| {
+            device.set_grid_button(
+                output,
+                x,
+                y,
+                setting.map_or(off_color(), |setting| {
+                    choice_color(settings, layer_index, layer, setting, x)
+                }),
+            )
+        })
+    })
+}
+
+// The notes of the layer, played by holding pads down.
+fn keyboard_to_device(
+    device: &dyn Device,
+    output: &dyn MidiOutput,
+    settings: &Settings,
+    player: &Player,
+    section_index: usize,
+    layer_index: usize,
+    layer: &Layer,
+) -> Result<(), AppError> {
+    let row_offset =
+        player.row_offset(settings, section_index, layer_index, layer);
+    (0..device.grid_height()).try_for_each(|y| {
+        (0..device.grid_width()).try_for_each(|x| {
+            let held = player
+                .keys
+                .iter()
+                .any(|key| key.x as usize == x && key.y as usize == y);
+            device.set_grid_button(
+                output,
+                x,
+                y,
+                key_row(settings, layer, row_offset, x, y)
+                    .map_or(off_color(), |row| {
+                        key_color(settings, layer_index, layer, row, held)
+                    }),
+            )
+        })
+    })
+}
+
+fn layer_to_device(
+    device: &dyn Device,
+    output: &dyn MidiOutput,
+    settings: &Settings,
+    player: &Player,
+    section_index: usize,
+    layer_index: usize,
+    layer: &Layer,
+) -> Result<(), AppError> {
+    device
+        .set_layer_button(
+            output,
+            layer_index,
+            Color {
+                style: ColorStyle::Steady100,
+                rgb: active_color(layer_index, player.active_layer_index),
+            },
+        )
+        .and_then(|()| {
+            if layer_index != player.active_layer_index {
+                Ok(())
+            } else {
+                match player.view {
+                    View::Steps
+                    | View::Euclid
+                    | View::Mutation
+                    | View::Morph => steps_to_device(
+                        device,
+                        output,
+                        settings,
+                        player,
+                        section_index,
+                        layer_index,
+                        layer,
+                    ),
+                    View::PageOverview => overview_to_device(
+                        device,
+                        output,
+                        settings,
+                        player,
+                        layer_index,
+                        layer,
+                    ),
+                    View::LayerSettings | View::Follow => {
+                        layer_settings_to_device(
+                            device,
+                            output,
+                            settings,
+                            &player.view,
+                            layer_index,
+                            layer,
+                        )
+                    }
+                    View::Automation => automation_to_device(
+                        device,
+                        output,
+                        settings,
+                        player,
+                        layer_index,
+                        layer,
+                    ),
+                    View::Keyboard => keyboard_to_device(
+                        device,
+                        output,
+                        settings,
+                        player,
+                        section_index,
+                        layer_index,
+                        layer,
+                    ),
+                }
+            }
+        })
+}
+
+fn section_to_device(
+    device: &dyn Device,
+    output: &dyn MidiOutput,
+    settings: &Settings,
+    player: &Player,
+    section_index: usize,
+    section: &Section,
+) -> Result<(), AppError> {
+    device
+        .set_section_button(
+            output,
+            section_index,
+            Color {
+                style: ColorStyle::Steady100,
+                rgb: section_color(section_index, player),
+            },
+        )
+        .and_then(|()| {
+            if section_index == player.active_section_index {
+                (0..device.layer_button_count()).try_for_each(|layer_index| {
+                    match section.layers.get(layer_index) {
+                        Some(layer) => layer_to_device(
+                            device,
+                            output,
+                            settings,
+                            player,
+                            section_index,
+                            layer_index,
+                            layer,
+                        ),
+                        None => device.set_layer_button(
+                            output,
+                            layer_index,
+                            off_color(),
+                        ),
+                    }
+                })
+            } else {
+                Ok(())
+            }
+        })
+}
+
+pub fn state_to_device(
+    device: &dyn Device,
+    output: &dyn MidiOutput,
+    state: &GlobalState,
+) -> Result<(), AppError> {
+    println!("State has changed...");
+    device
+        .set_play_button(output, play_button_color(&state.player))
+        .and_then(|()| {
+            (0..device.section_button_count()).try_for_each(|section_index| {
+                match shown_section(state, section_index) {
+                    Some(section) => section_to_device(
+                        device,
+                        output,
+                        &state.settings,
+                        &state.player,
+                        section_index,
+                        &section,
+                    ),
+                    None => device.set_section_button(
+                        output,
+                        section_index,
+                        off_color(),
+                    ),
+                }
+            })
+        })
+}
+
+// Order dictates the layer.
+const LAYER_COLORS: &[u32] = &[
+    0x0000ff, 0x00ffff, 0x00ff00, 0xffff00, 0xff0000, 0xff00ff, 0xffaa00,
+    0xffffff,
+];
+
+fn off_color() -> Color {
+    Color {
+        rgb: 0,
+        style: ColorStyle::Steady100,
+    }
+}
+
+fn layer_color(layer: usize) -> u32 {
+    LAYER_COLORS[layer % LAYER_COLORS.len()]
+}
+
+// Drum voices bring their own colors. Everything else is in the color of the
+// layer.
+fn row_color(layer_index: usize, layer: &Layer, row: usize) -> u32 {
+    match &layer.kind {
+        LayerKind::Drum { voices } => voices
+            .get(row)
+            .map_or(layer_color(layer_index), |voice| voice.color),
+        LayerKind::Melodic | LayerKind::Arpeggio | LayerKind::Follower => {
+            layer_color(layer_index)
+        }
+    }
+}
+
+// The morph view shows the active section as it plays. Everywhere else steps
+// are shown as they are written, ready to be edited.
+fn shown_section(
+    state: &GlobalState,
+    section_index: usize,
+) -> Option<Cow<'_, Section>> {
+    match state.player.view {
+        View::Morph => played_section(state, section_index),
+        View::Steps
+        | View::PageOverview
+        | View::LayerSettings
+        | View::Automation
+        | View::Keyboard
+        | View::Euclid
+        | View::Mutation
+        | View::Follow => state.sections.get(section_index).map(Cow::Borrowed),
+    }
+}
+
+// The section being morphed towards blinks.
+fn section_color(section_index: usize, player: &Player) -> u32 {
+    if player.morph.section == Some(section_index) {
+        2
+    } else {
+        active_color(section_index, player.active_section_index)
+    }
+}
+
+fn active_color(current: usize, active: usize) -> u32 {
+    if current == active {
+        1
+    } else {
+        0
+    }
+}
+
+fn note_color(
+    shown: &ShownSteps,
+    note_index: usize,
+    note: &Note,
+    octave: usize,
+    length_pos: usize,
+    // Whether the pad is on the top or bottom row with notes out of sight
+    // past it, in which case it glows faintly if it has nothing else to show.
+    edge: bool,
+) -> Color {
+    let rgb = row_color(shown.layer_index, shown.layer, octave);
+    if octave >= shown.layer.row_count(shown.settings) {
+        // Past the last row.
+        off_color()
+    } else if shown.arpeggio == Some((note_index, octave)) {
+        // The note an arpeggio is on.
+        Color {
+            rgb: 0xffffff,
+            style: ColorStyle::Steady100,
+        }
+    } else if shown.playhead == Some(note_index) {
+        // Active note and interval.
+        if note.length > 0 && note.octaves.contains(&octave) {
+            Color {
+                rgb,
+                style: ColorStyle::Steady95,
+            }
+            // Interval active here.
+        } else {
+            Color {
+                rgb,
+                style: ColorStyle::Steady50,
+            }
+        }
+        // Active note with nothing else.
+    } else if note.length > 0 && note.octaves.contains(&octave) {
+        if length_pos == 0 {
+            // Where the note begins.
+            Color {
+                rgb,
+                style: ColorStyle::Steady75,
+            }
+        } else {
+            // Any part of a longer note.
+            Color {
+                rgb,
+                style: ColorStyle::Steady65,
+            }
+        }
+        // Vacant, but with notes further along.
+    } else if edge {
+        Color {
+            rgb,
+            style: ColorStyle::Steady10,
+        }
+        // Vacant.
+    } else {
+        off_color()
+    }
+}
+
+// Roots stand out in the keyboard view, the way the start of a note does in
+// the steps, and the rest of the scale is dimmer. Drum voices are all in their
+// own colors.
+fn key_color(
+    settings: &Settings,
+    layer_index: usize,
+    layer: &Layer,
+    row: usize,
+    held: bool,
+) -> Color {
+    let rgb = row_color(layer_index, layer, row);
+    let pitch = layer.pitch(row);
+    if held {
+        Color {
+            rgb: 0xffffff,
+            style: ColorStyle::Steady95,
+        }
+    } else if let LayerKind::Drum { .. } = layer.kind {
+        Color {
+            rgb,
+            style: ColorStyle::Steady50,
+        }
+    } else if pitch.is_some_and(|pitch| settings.scale.is_root(pitch)) {
+        Color {
+            rgb,
+            style: ColorStyle::Steady75,
+        }
+    } else {
+        Color {
+            rgb,
+            style: ColorStyle::Steady25,
+        }
+    }
+}
+
+fn page_color(
+    settings: &Settings,
+    layer_index: usize,
+    layer: &Layer,
+    active_page: usize,
+    page: usize,
+) -> Color {
+    let has_content = layer
+        .notes
+        .iter()
+        .take(layer.length)
+        .skip(page * settings.page_length)
+        .take(settings.page_length)
+        .any(|note| note.length > 0);
+    if page >= settings.page_count()
+        || page * settings.page_length >= layer.length
+    {
+        // There is no such page, or the layer ends before it.
+        off_color()
+    } else if page == active_page {
+        Color {
+            rgb: 0xffffff,
+            style: ColorStyle::Steady95,
+        }
+    } else if has_content {
+        Color {
+            rgb: layer_color(layer_index),
+            style: ColorStyle::Steady75,
+        }
+    } else {
+        // An empty page, dim enough to tell it apart from no page at all.
+        Color {
+            rgb: 0x1e1e1e,
+            style: ColorStyle::Steady10,
+        }
+    }
+}
+
+fn choice_color(
+    settings: &Settings,
+    layer_index: usize,
+    layer: &Layer,
+    setting: LayerSetting,
+    choice: usize,
+) -> Color {
+    if choice >= setting.choice_count(settings) {
+        // There is no such choice.
+        off_color()
+    } else if setting.chosen(layer) == Some(choice) {
+        Color {
+            rgb: 0xffffff,
+            style: ColorStyle::Steady95,
+        }
+    } else {
+        Color {
+            rgb: layer_color(layer_index),
+            style: ColorStyle::Steady25,
+        }
+    }
+}
+
+// After a tap the play button blinks in time, so the tempo can be checked.
+fn play_button_color(player: &Player) -> Color {
+    match player.tempo.blink(player.clock) {
+        Some(lit) => Color {
+            rgb: if lit { 0x1 } else { 0x0 },
+            style: ColorStyle::Steady100,
+        },
+        None => play_mode_color(player.play_mode.clone()),
+    }
+}
+
+fn play_mode_color(play_mode: PlayMode) -> Color {
+    match play_mode {
+        PlayMode::Playing => Color {
+            rgb: 0x1,
+            style: ColorStyle::Steady100,
+        },
+        PlayMode::Paused => Color {
+            rgb: 0x1,
+            style: ColorStyle::Blink2,
+        },
+        PlayMode::Stopped => Color {
+            rgb: 0x0,
+            style: ColorStyle::Steady100,
+        },
+    }
+}
@@ -0,0 +1,142 @@
+// Note names as given on the command line, from C.
+const NOTE_NAMES: [&str; 12] = [
+    "c", "c#", "d", "d#", "e", "f", "f#", "g", "g#", "a", "a#", "b",
+];
+
+/**
+ * Which notes of an octave belong to a scale, as semitones above its root.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Mode {
+    #[default]
+    Major,
+    Minor,
+    Dorian,
+    Mixolydian,
+    MajorPentatonic,
+    MinorPentatonic,
+    Chromatic,
+}
+
+const MODES: [(&str, Mode); 7] = [
+    ("major", Mode::Major),
+    ("minor", Mode::Minor),
+    ("dorian", Mode::Dorian),
+    ("mixolydian", Mode::Mixolydian),
+    ("major-pentatonic", Mode::MajorPentatonic),
+    ("minor-pentatonic", Mode::MinorPentatonic),
+    ("chromatic", Mode::Chromatic),
+];
+
+impl Mode {
+    pub fn intervals(&self) -> &'static [u8] {
+        match self {
+            Mode::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Mode::Minor => &[0, 2, 3, 5, 7, 8, 10],
+            Mode::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+            Mode::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+            Mode::MajorPentatonic => &[0, 2, 4, 7, 9],
+            Mode::MinorPentatonic => &[0, 3, 5, 7, 10],
+            Mode::Chromatic => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+        }
+    }
+}
+
+/**
+ * The scale the project is played in. Melodic rows are still MIDI notes, so
+ * nothing stops a note outside of the scale from being written. The scale is
+ * there for anything that places notes on its own, such as the grid keyboard.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Scale {
+    // The pitch class of the root, where 0 is C.
+    pub root: u8,
+    pub mode: Mode,
+}
+
+impl Scale {
+    // Takes names such as "d-minor" or "f#-major-pentatonic".
+    pub fn from_name(name: &str) -> Option<Scale> {
+        let name = name.to_lowercase();
+        let (root, mode) = name.split_once('-')?;
+        Some(Scale {
+            root: NOTE_NAMES.iter().position(|x| *x == root)? as u8,
+            mode: MODES.iter().find(|(x, _)| *x == mode)?.1,
+        })
+    }
+
+    fn degree_count(&self) -> i32 {
+        self.mode.intervals().len() as i32
+    }
+
+    pub fn is_root(&self, pitch: u8) -> bool {
+        pitch % 12 == self.root
+    }
+
+    // The pitch some degrees of the scale away from a pitch, counting from the
+    // degree at or below it. Pitches outside of the scale land in it.
+    pub fn shift(&self, pitch: u8, degrees: i32) -> Option<u8> {
+        let interval = (pitch + 12 - self.root) % 12;
+        let degree = self
+            .mode
+            .intervals()
+            .iter()
+            .rposition(|x| *x <= interval)
+            .unwrap_or(0);
+        self.degree_pitch(pitch, degree as i32 + degrees)
+    }
+
+    // The pitch this many degrees of the scale above the root in the octave
+    // that starts on or below the given pitch. Negative degrees go down.
+    pub fn degree_pitch(&self, from: u8, degree: i32) -> Option<u8> {
+        let root =
+            from as i32 - (from as i32 - self.root as i32).rem_euclid(12);
+        let octave = degree.div_euclid(self.degree_count());
+        let interval = self.mode.intervals()
+            [degree.rem_euclid(self.degree_count()) as usize];
+        u8::try_from(root + octave * 12 + interval as i32)
+            .ok()
+            .filter(|pitch| *pitch < 128)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scales_are_named_root_first() {
+        assert_eq!(
+            Scale::from_name("F#-Major-Pentatonic"),
+            Some(Scale {
+                root: 6,
+                mode: Mode::MajorPentatonic,
+            })
+        );
+        assert_eq!(Scale::from_name("h-major"), None);
+        assert_eq!(Scale::from_name("minor"), None);
+    }
+
+    #[test]
+    fn shifting_moves_along_the_scale() {
+        let c_major = Scale::default();
+        assert_eq!(c_major.shift(60, 2), Some(64));
+        assert_eq!(c_major.shift(64, -1), Some(62));
+        assert_eq!(c_major.shift(71, 1), Some(72));
+        assert_eq!(c_major.shift(60, -8), Some(47));
+    }
+
+    #[test]
+    fn shifting_lands_notes_outside_the_scale_in_it() {
+        let c_major = Scale::default();
+        assert_eq!(c_major.shift(61, 0), Some(60));
+        assert_eq!(c_major.shift(66, 1), Some(67));
+    }
+
+    #[test]
+    fn shifting_stops_at_the_ends_of_midi() {
+        let c_major = Scale::default();
+        assert_eq!(c_major.shift(127, 1), None);
+        assert_eq!(c_major.shift(0, -1), None);
+    }
+}
@@ -0,0 +1,621 @@
+use crate::device::MidiOutput;
+use crate::effects::{
+    is_fill, is_held, is_played, played_interval, stretch, Effect,
+};
+use crate::error::AppError;
+use crate::groove::MAX_GROOVE_TIMING;
+use crate::morph::played_section;
+use crate::playback::{
+    arpeggio_note, arpeggio_ticks, layer_step, layer_steps_taken, step_fires,
+    step_start_tick, step_ticks, PlayingLayer,
+};
+use crate::state::{
+    GlobalState, HeldKey, Layer, LayerKind, Note, PlayMode, Settings,
+};
+use std::collections::HashMap;
+
+const NOTE_ON_STATUS: u32 = 0x20900000;
+const NOTE_OFF_STATUS: u32 = 0x20800000;
+const CONTROL_CHANGE_STATUS: u32 = 0x20b00000;
+// How hard notes are played before any groove is laid over them.
+const DEFAULT_VELOCITY: i32 = 100;
+// Each layer plays on a MIDI channel of its own, wrapping past the last one.
+const CHANNEL_COUNT: usize = 16;
+
+/**
+ * A note to be played, and how many ticks to hold it for.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NoteEvent {
+    pub channel: u8,
+    pub pitch: u8,
+    pub velocity: u8,
+    pub ticks: usize,
+}
+
+/**
+ * Something to send to the instrument on a tick. Controllers locked on a step
+ * are sent ahead of its notes.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    Control { channel: u8, cc: u8, value: u8 },
+    Note(NoteEvent),
+}
+
+fn layer_channel(layer_index: usize) -> u8 {
+    (layer_index % CHANNEL_COUNT) as u8
+}
+
+// How far off of its step a note is played, in ticks. Swing pushes back every
+// other step, a groove can push any step either way, and so can the note.
+fn timing_offset(
+    settings: &Settings,
+    layer: &Layer,
+    note: &Note,
+    steps_taken: usize,
+    step_ticks: usize,
+) -> isize {
+    let percent_of_step =
+        |percent: i32| (percent as f32 * step_ticks as f32 / 100.0).round();
+    let swing = layer.swing.unwrap_or(settings.swing).saturating_sub(50);
+    let swing_offset = if steps_taken % 2 == 1 {
+        percent_of_step(swing as i32 * 2)
+    } else {
+        0.0
+    };
+    let groove_offset = settings
+        .groove
+        .as_ref()
+        .and_then(|groove| groove.step(steps_taken))
+        .map_or(0.0, |step| {
+            percent_of_step(
+                step.timing.clamp(-MAX_GROOVE_TIMING, MAX_GROOVE_TIMING),
+            )
+        });
+    (swing_offset + groove_offset + percent_of_step(note.nudge)) as isize
+}
+
+fn velocity(settings: &Settings, steps_taken: usize) -> u8 {
+    let groove_velocity = settings
+        .groove
+        .as_ref()
+        .and_then(|groove| groove.step(steps_taken))
+        .map_or(0, |step| step.velocity);
+    (DEFAULT_VELOCITY + groove_velocity).clamp(1, 127) as u8
+}
+
+// What a layer plays on a tick into its section. Steps can be played as
+// much as a step early or late, so the steps either side are looked at too.
+fn layer_events_at(
+    playing: &PlayingLayer,
+    transpose: i32,
+    tick: usize,
+) -> Vec<Event> {
+    let PlayingLayer {
+        settings,
+        section_index,
+        layer_index,
+        layer,
+        ..
+    } = *playing;
+    let step_ticks = step_ticks(layer);
+    // Swing, a groove or a nudge can each put a step off by half a step, and
+    // strumming a chord can stretch it by another half, so look back far
+    // enough to find any step still being played. Arpeggios go on playing for
+    // as long as their notes last.
+    let lookback = match layer.kind {
+        LayerKind::Arpeggio => layer
+            .notes
+            .iter()
+            .map(|note| note.length)
+            .max()
+            .unwrap_or(0),
+        LayerKind::Melodic | LayerKind::Drum { .. } | LayerKind::Follower => 0,
+    } + 2;
+    let first =
+        layer_steps_taken(layer, tick.saturating_sub(lookback * step_ticks));
+    let last = layer_steps_taken(layer, tick + step_ticks);
+    (first..=last)
+        .filter(|steps_taken| step_fires(playing, *steps_taken))
+        .filter_map(|steps_taken| {
+            let step = layer_step(
+                settings,
+                section_index,
+                layer_index,
+                layer,
+                steps_taken,
+            );
+            layer
+                .notes
+                .get(step)
+                .filter(|note| note.length > 0 || !note.locks.is_empty())
+                .map(|note| (steps_taken, note))
+        })
+        .flat_map(|(steps_taken, note)| {
+            // Nothing is played before the section starts.
+            let start = (step_start_tick(layer, steps_taken) as isize
+                + timing_offset(settings, layer, note, steps_taken, step_ticks))
+            .max(0) as usize;
+            match layer.kind {
+                LayerKind::Arpeggio => arpeggio_events(
+                    playing,
+                    transpose,
+                    note,
+                    steps_taken,
+                    start,
+                    tick,
+                ),
+                LayerKind::Melodic
+                | LayerKind::Drum { .. }
+                | LayerKind::Follower => chord_events(
+                    playing,
+                    transpose,
+                    note,
+                    steps_taken,
+                    start,
+                    tick,
+                ),
+            }
+        })
+        .collect()
+}
+
+// The notes of a step are played together, or close to it when strummed.
+fn chord_events(
+    playing: &PlayingLayer,
+    transpose: i32,
+    note: &Note,
+    steps_taken: usize,
+    start: usize,
+    tick: usize,
+) -> Vec<Event> {
+    let PlayingLayer {
+        settings,
+        layer_index,
+        layer,
+        ..
+    } = *playing;
+    let step_ticks = step_ticks(layer);
+    let channel = layer_channel(layer_index);
+    // Ratchets split the step evenly, and the last one holds on for whatever is
+    // left of the note.
+    let ratchets = note.ratchets.max(1);
+    let ratchet_ticks = (step_ticks / ratchets).max(1);
+    let note_ticks = note.length * step_ticks;
+    let mut pitches = note
+        .octaves
+        .iter()
+        .filter(|_| note.length > 0)
+        .filter_map(|row| layer.transposed_pitch(*row, transpose))
+        .collect::<Vec<u8>>();
+    pitches.sort();
+    let strum = strum_offsets(layer, pitches.len(), step_ticks);
+    (0..ratchets)
+        .flat_map(|ratchet| {
+            let ratchet_start = start + ratchet * ratchet_ticks;
+            let ticks = if ratchet + 1 == ratchets {
+                note_ticks.saturating_sub(ratchet * ratchet_ticks)
+            } else {
+                ratchet_ticks
+            };
+            let controls = note
+                .locks
+                .iter()
+                .filter(|_| ratchet == 0 && ratchet_start == tick)
+                .map(|(cc, value)| Event::Control {
+                    channel,
+                    cc: *cc,
+                    value: *value,
+                });
+            // Each note of a strummed chord is held until the chord is let go
+            // of, however late it started.
+            let notes = pitches
+                .iter()
+                .zip(&strum)
+                .filter(|(_, offset)| ratchet_start + *offset == tick)
+                .map(|(pitch, offset)| {
+                    Event::Note(NoteEvent {
+                        channel,
+                        pitch: *pitch,
+                        velocity: velocity(settings, steps_taken),
+                        ticks: ticks.saturating_sub(*offset).max(1),
+                    })
+                });
+            controls.chain(notes).collect::<Vec<Event>>()
+        })
+        .collect::<Vec<Event>>()
+}
+
+// An arpeggio plays the notes of a step one at a time, for as long as the note
+// of the step lasts. Ratchets and strums have no part in it.
+fn arpeggio_events(
+    playing: &PlayingLayer,
+    transpose: i32,
+    note: &Note,
+    steps_taken: usize,
+    start: usize,
+    tick: usize,
+) -> Vec<Event> {
+    let PlayingLayer {
+        settings,
+        section_index,
+        layer_index,
+        layer,
+        ..
+    } = *playing;
+    let channel = layer_channel(layer_index);
+    let note_ticks = note.length * step_ticks(layer);
+    let arpeggio_ticks = arpeggio_ticks(layer);
+    let controls =
+        note.locks
+            .iter()
+            .filter(|_| start == tick)
+            .map(|(cc, value)| Event::Control {
+                channel,
+                cc: *cc,
+                value: *value,
+            });
+    let notes = tick
+        .checked_sub(start)
+        .filter(|elapsed| {
+            *elapsed < note_ticks && elapsed % arpeggio_ticks == 0
+        })
+        .and_then(|elapsed| {
+            arpeggio_note(
+                settings,
+                section_index,
+                layer_index,
+                layer,
+                note,
+                steps_taken,
+                elapsed / arpeggio_ticks,
+            )
+            .and_then(|(row, octave)| {
+                layer.transposed_pitch(row, transpose + octave as i32 * 12)
+            })
+            .map(|pitch| {
+                Event::Note(NoteEvent {
+                    channel,
+                    pitch,
+                    velocity: velocity(settings, steps_taken),
+                    ticks: arpeggio_ticks.min(note_ticks - elapsed),
+                })
+            })
+        });
+    controls.chain(notes).collect()
+}
+
+/**
+ * Everything played some number of ticks into the active section, across
+ * every layer of it, along with the instrument of the layer playing it.
+ */
+pub fn events_at(state: &GlobalState, tick: usize) -> Vec<(String, Event)> {
+    let settings = &state.settings;
+    let section_index = state.player.active_section_index;
+    played_section(state, section_index).map_or(vec![], |section| {
+        section
+            .layers
+            .iter()
+            .enumerate()
+            // Soloing mutes every layer but the active one.
+            .filter(|(layer_index, _)| {
+                !is_held(&state.player, Effect::Solo)
+                    || *layer_index == state.player.active_layer_index
+            })
+            .flat_map(|(layer_index, layer)| {
+                layer_events_at(
+                    &PlayingLayer {
+                        settings,
+                        fill: is_fill(&state.player),
+                        section_index,
+                        layer_index,
+                        layer,
+                        loops: &state.player.layer_loops(layer_index),
+                    },
+                    state.player.transpose,
+                    tick,
+                )
+                .into_iter()
+                .map(|event| (layer.instrument.clone(), event))
+            })
+            .collect()
+    })
+}
+
+// How far after the lowest note of a chord each note is played, in ticks. A
+// strum is spread evenly from the lowest note to the highest.
+fn strum_offsets(layer: &Layer, notes: usize, step_ticks: usize) -> Vec<usize> {
+    let strum_ticks =
+        (layer.strum as f32 * step_ticks as f32 / 100.0).round() as usize;
+    (0..notes)
+        .map(|note| strum_ticks * note / (notes - 1).max(1))
+        .collect()
+}
+
+/**
+ * The instruments layers are played on. A layer plays on the instrument it is
+ * named after, if there is one, and on the default instrument otherwise.
+ */
+#[derive(Default)]
+pub struct Instruments {
+    pub default: Option<Box<dyn MidiOutput + Send>>,
+    pub named: HashMap<String, Box<dyn MidiOutput + Send>>,
+}
+
+impl Instruments {
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.named.is_empty()
+    }
+
+    // Anything for an instrument that isn't there goes unplayed.
+    fn send(&self, instrument: &str, packet: u32) -> Result<(), AppError> {
+        match self.named.get(instrument).or(self.default.as_ref()) {
+            Some(output) => output.send(packet),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct SoundingNote {
+    instrument: String,
+    channel: u8,
+    pitch: u8,
+    off_interval: usize,
+}
+
+/**
+ * The Scheduler plays the pattern out to instruments as the clock ticks. It
+ * remembers which notes are sounding so it can let go of them when they are
+ * done, or when playing stops.
+ *
+ * Anything that changes when a note is played without changing the pattern,
+ * such as swing or a groove, is worked out here.
+ *
+ * Keys held on the grid are played too, as they are pressed and let go of,
+ * whether or not the pattern is playing.
+ */
+#[derive(Default)]
+pub struct Scheduler {
+    last_interval: Option<usize>,
+    sounding: Vec<SoundingNote>,
+    // The keys held, and the instrument each is sounding on.
+    keys: Vec<(HeldKey, String)>,
+}
+
+impl Scheduler {
+    // Called with every new state. Only new ticks of the clock are played.
+    pub fn update(
+        &mut self,
+        instruments: &Instruments,
+        state: &GlobalState,
+    ) -> Result<(), AppError> {
+        self.update_keys(instruments, state)?;
+        let interval = state.player.interval;
+        match state.player.play_mode {
+            PlayMode::Playing if self.last_interval != Some(interval) => {
+                // Playing from the top again lets go of anything still
+                // sounding from before.
+                let restarted =
+                    self.last_interval.is_some_and(|last| interval < last);
+                self.last_interval = Some(interval);
+                self.release(instruments, |note| {
+                    restarted || note.off_interval <= interval
+                })?;
+                // Notes are let go of by the clock, but played from wherever
+                // any effects held have moved playback to.
+                let played = is_played(&state.player, interval)
+                    .then(|| played_interval(&state.player, interval));
+                let stretch = stretch(&state.player);
+                played
+                    .map_or(vec![], |played| events_at(state, played))
+                    .into_iter()
+                    .try_for_each(|(instrument, event)| match event {
+                        Event::Control { channel, cc, value } => instruments
+                            .send(
+                                &instrument,
+                                CONTROL_CHANGE_STATUS
+                                    | (channel as u32) << 16
+                                    | (cc as u32) << 8
+                                    | value as u32,
+                            ),
+                        Event::Note(note) => self.play(
+                            instruments,
+                            instrument,
+                            interval,
+                            NoteEvent {
+                                ticks: note.ticks * stretch,
+                                ..note
+                            },
+                        ),
+                    })
+            }
+            PlayMode::Playing => Ok(()),
+            PlayMode::Paused | PlayMode::Stopped => {
+                self.last_interval = None;
+                self.release(instruments, |_| true)
+            }
+        }
+    }
+
+    // A key is let go of on the instrument it was pressed on, even if its
+    // layer has moved to another since.
+    fn update_keys(
+        &mut self,
+        instruments: &Instruments,
+        state: &GlobalState,
+    ) -> Result<(), AppError> {
+        let message = |status: u32, key: &HeldKey, velocity: u32| {
+            status
+                | (layer_channel(key.layer_index) as u32) << 16
+                | (key.pitch as u32) << 8
+                | velocity
+        };
+        let keys = &state.player.keys;
+        let section = state.sections.get(state.player.active_section_index);
+        let pressed = keys
+            .iter()
+            .filter(|key| !self.keys.iter().any(|(held, _)| held == *key))
+            .map(|key| {
+                let instrument = section
+                    .and_then(|section| section.layers.get(key.layer_index))
+                    .map_or(String::new(), |layer| layer.instrument.clone());
+                (key.clone(), instrument)
+            })
+            .collect::<Vec<(HeldKey, String)>>();
+        let (released, held): (Vec<_>, Vec<_>) = self
+            .keys
+            .drain(..)
+            .partition(|(key, _)| !keys.contains(key));
+        released
+            .iter()
+            .map(|(key, instrument)| {
+                instruments.send(instrument, message(NOTE_OFF_STATUS, key, 0))
+            })
+            .chain(pressed.iter().map(|(key, instrument)| {
+                instruments.send(
+                    instrument,
+                    message(NOTE_ON_STATUS, key, DEFAULT_VELOCITY as u32),
+                )
+            }))
+            .collect::<Result<(), AppError>>()?;
+        self.keys = held.into_iter().chain(pressed).collect();
+        Ok(())
+    }
+
+    fn play(
+        &mut self,
+        instruments: &Instruments,
+        instrument: String,
+        interval: usize,
+        note: NoteEvent,
+    ) -> Result<(), AppError> {
+        // Cut the same note short if it is still sounding, or its note off
+        // would cut this one short instead.
+        self.release(instruments, |x| {
+            x.instrument == instrument
+                && x.channel == note.channel
+                && x.pitch == note.pitch
+        })?;
+        instruments.send(
+            &instrument,
+            NOTE_ON_STATUS
+                | (note.channel as u32) << 16
+                | (note.pitch as u32) << 8
+                | note.velocity as u32,
+        )?;
+        self.sounding.push(SoundingNote {
+            instrument,
+            channel: note.channel,
+            pitch: note.pitch,
+            off_interval: interval + note.ticks,
+        });
+        Ok(())
+    }
+
+    fn release<F: Fn(&SoundingNote) -> bool>(
+        &mut self,
+        instruments: &Instruments,
+        released: F,
+    ) -> Result<(), AppError> {
+        let (done, sounding): (Vec<SoundingNote>, Vec<SoundingNote>) =
+            self.sounding.drain(..).partition(|x| released(x));
+        self.sounding = sounding;
+        done.iter().try_for_each(|note| {
+            instruments.send(
+                &note.instrument,
+                NOTE_OFF_STATUS
+                    | (note.channel as u32) << 16
+                    | (note.pitch as u32) << 8,
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::Action;
+    use crate::groove::{Groove, GrooveStep};
+    use crate::playback::TICKS_PER_STEP;
+    use crate::reducer::reducer;
+    use crate::state::initial_state;
+    use std::sync::{Arc, Mutex};
+
+    struct SharedOutput(Arc<Mutex<Vec<u32>>>);
+
+    impl MidiOutput for SharedOutput {
+        fn send(&self, packet: u32) -> Result<(), AppError> {
+            self.0.lock().unwrap().push(packet);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn layers_play_on_the_instrument_they_are_named_after() {
+        let mut state = initial_state(Settings::default());
+        state.sections[0].layers[1].instrument = "Bass".to_string();
+        let state = [
+            Action::GridToggle { x: 0, y: 0 },
+            Action::LayerSelect { pos: 1 },
+            Action::GridToggle { x: 0, y: 0 },
+            Action::PlayModeChange(PlayMode::Playing),
+        ]
+        .into_iter()
+        .fold(state, reducer);
+        let default = Arc::new(Mutex::new(vec![]));
+        let bass = Arc::new(Mutex::new(vec![]));
+        let instruments = Instruments {
+            default: Some(Box::new(SharedOutput(default.clone()))),
+            named: HashMap::from([(
+                "Bass".to_string(),
+                Box::new(SharedOutput(bass.clone()))
+                    as Box<dyn MidiOutput + Send>,
+            )]),
+        };
+        Scheduler::default().update(&instruments, &state).unwrap();
+        let channels = |packets: &Arc<Mutex<Vec<u32>>>| {
+            packets
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|packet| (packet >> 16) & 0xf)
+                .collect::<Vec<u32>>()
+        };
+        assert_eq!(channels(&default), vec![0]);
+        assert_eq!(channels(&bass), vec![1]);
+    }
+
+    #[test]
+    fn swing_and_grooves_lean_off_the_step() {
+        let mut settings = Settings {
+            swing: 75,
+            ..Settings::default()
+        };
+        let layer =
+            initial_state(settings.clone()).sections[0].layers[0].clone();
+        let note = Note::default();
+        let ticks = TICKS_PER_STEP as isize;
+        assert_eq!(
+            timing_offset(&settings, &layer, &note, 0, TICKS_PER_STEP),
+            0
+        );
+        assert_eq!(
+            timing_offset(&settings, &layer, &note, 1, TICKS_PER_STEP),
+            ticks / 2
+        );
+        settings.swing = 50;
+        settings.groove = Some(Groove {
+            name: "Early".to_string(),
+            steps: vec![GrooveStep {
+                timing: -200,
+                velocity: 0,
+            }],
+        });
+        assert_eq!(
+            timing_offset(&settings, &layer, &note, 0, TICKS_PER_STEP),
+            -ticks / 2
+        );
+    }
+}
@@ -1,6 +1,18 @@
-pub const NOTE_COUNT: usize = 8;
+use crate::chord::Chord;
+use crate::effects::HeldEffect;
+use crate::euclid::Euclid;
+use crate::follow::Follow;
+use crate::groove::Groove;
+use crate::morph::Morph;
+use crate::mutation::Mutation;
+use crate::playback::LayerLoops;
+use crate::scale::Scale;
+use crate::tempo::Tempo;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
 
-#[derive(Clone, Default)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub enum PlayMode {
     Paused,
     Playing,
@@ -8,20 +20,415 @@ pub enum PlayMode {
     Stopped,
 }
 
-#[derive(Clone, Debug)]
+/**
+ * What playing a keyboard records into the active layer.
+ */
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum RecordMode {
+    #[default]
+    Off,
+    // Keys land on the step nearest the playhead, while playing.
+    RealTime,
+    // Keys land on the step under the cursor, which then moves on a step.
+    StepEntry,
+}
+
+impl RecordMode {
+    pub fn next(&self) -> RecordMode {
+        match self {
+            RecordMode::Off => RecordMode::RealTime,
+            RecordMode::RealTime => RecordMode::StepEntry,
+            RecordMode::StepEntry => RecordMode::Off,
+        }
+    }
+}
+
+/**
+ * What the pads of the grid are showing.
+ */
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum View {
+    // Each pad is a step on the current page of the active layer.
+    #[default]
+    Steps,
+    // Each pad is a whole page of the active layer.
+    PageOverview,
+    // Each row is a setting of the active layer, and each pad a choice for it.
+    LayerSettings,
+    // Each column is how far a controller is turned on a step of the active
+    // layer.
+    Automation,
+    // Each pad plays a note of the active layer for as long as it is held.
+    Keyboard,
+    // The steps, as in the steps view, but pads pick a row to fill with a
+    // Euclidean rhythm and faders shape the rhythm.
+    Euclid,
+    // The steps, as in the steps view, with faders setting how the active
+    // layer mutates.
+    Mutation,
+    // Each row is a setting of what the active layer follows, as in the layer
+    // settings view.
+    Follow,
+    // The steps of the active section as it plays part way through a morph.
+    // Section buttons pick the section to morph towards, and the first fader
+    // how far along it is.
+    Morph,
+}
+
+/**
+ * When a step is played. Loops count how many times a layer has come back
+ * around since its section started.
+ */
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum Condition {
+    #[default]
+    Always,
+    // Played this percentage of the time, at random.
+    Chance {
+        percent: usize,
+    },
+    // Played on the nth of every so many loops, such as 3:4.
+    Every {
+        nth: usize,
+        of: usize,
+    },
+    // Played only while a fill is going.
+    Fill,
+    // Played only the first time around.
+    First,
+    // Played only when the last step with a condition on the layer wasn't.
+    NotPrevious,
+}
+
+const CHANCES: [usize; 3] = [75, 50, 25];
+// Up to every fourth loop, which is as far as anyone counts.
+const MAX_EVERY: usize = 4;
+
+impl Condition {
+    /**
+     * Picking a condition of the same kind again goes through the choices it
+     * has, such as 1:2, 2:2, 1:3 and so on. Picking another kind starts from
+     * its first choice.
+     */
+    pub fn next_of_kind(&self, kind: Condition) -> Condition {
+        match (*self, kind) {
+            (Condition::Chance { percent }, Condition::Chance { .. }) => {
+                let next = CHANCES
+                    .iter()
+                    .position(|x| *x == percent)
+                    .map_or(0, |x| (x + 1) % CHANCES.len());
+                Condition::Chance {
+                    percent: CHANCES[next],
+                }
+            }
+            (Condition::Every { nth, of }, Condition::Every { .. }) => {
+                if nth < of {
+                    Condition::Every { nth: nth + 1, of }
+                } else if of < MAX_EVERY {
+                    Condition::Every { nth: 1, of: of + 1 }
+                } else {
+                    Condition::Every { nth: 1, of: 2 }
+                }
+            }
+            (_, Condition::Chance { .. }) => Condition::Chance {
+                percent: CHANCES[0],
+            },
+            (_, Condition::Every { .. }) => Condition::Every { nth: 1, of: 2 },
+            (_, kind) => kind,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Note {
     pub octaves: Vec<usize>,
     pub length: usize,
+    // How far off of its step the note is played, as a percentage of a step.
+    // Negative is early.
+    pub nudge: i32,
+    // How many times the note is struck within its step.
+    pub ratchets: usize,
+    pub condition: Condition,
+    // Controller values sent just before the note plays, by controller number.
+    pub locks: BTreeMap<u8, u8>,
+}
+
+impl Default for Note {
+    fn default() -> Self {
+        Note {
+            octaves: vec![],
+            length: 0,
+            nudge: 0,
+            ratchets: 1,
+            condition: Condition::Always,
+            locks: BTreeMap::new(),
+        }
+    }
+}
+
+// Notes can be nudged up to half a step either way. Any further and they're
+// on another step.
+pub const MAX_NUDGE: i32 = 50;
+pub const MAX_RATCHETS: usize = 4;
+
+/**
+ * A DrumVoice is what a single row of a drum layer plays.
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DrumVoice {
+    // Only for anyone reading a drum map. The device has nowhere to show it,
+    // so voices are told apart on the grid by their colors.
+    pub name: String,
+    // The MIDI note that triggers the voice.
+    pub pitch: u8,
+    pub color: u32,
+}
+
+/**
+ * How the rows of a layer are read.
+ */
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum LayerKind {
+    // Each row is a pitch. Row 60 is middle C.
+    #[default]
+    Melodic,
+    // Each row is a drum voice, bound to its own note.
+    Drum {
+        voices: Vec<DrumVoice>,
+    },
+    // Each row is a pitch, as in a melodic layer, but the notes of each step
+    // are played one at a time as an arpeggio.
+    Arpeggio,
+    // Each row is a pitch, but the notes come from another layer of the
+    // section rather than from steps of its own.
+    Follower,
+}
+
+/**
+ * The General MIDI drum map, or the bits of it anyone actually uses. Kick is on
+ * the bottom row.
+ */
+pub fn general_midi_drum_voices() -> Vec<DrumVoice> {
+    [
+        ("Kick", 36, 0xff0000),
+        ("Snare", 38, 0xffaa00),
+        ("Clap", 39, 0xffff00),
+        ("Closed hat", 42, 0x00ffff),
+        ("Open hat", 46, 0x0055ff),
+        ("Low tom", 45, 0x00ff00),
+        ("High tom", 50, 0x88ff4c),
+        ("Crash", 49, 0xff00ff),
+        ("Ride", 51, 0x7a00ff),
+        ("Rim", 37, 0xffffff),
+    ]
+    .iter()
+    .map(|(name, pitch, color)| DrumVoice {
+        name: name.to_string(),
+        pitch: *pitch,
+        color: *color,
+    })
+    .collect()
+}
+
+/**
+ * The order a layer plays its steps in.
+ */
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum Direction {
+    #[default]
+    Forward,
+    Reverse,
+    // Forward then back again, without playing the ends twice.
+    PingPong,
+    // Any step, every time.
+    Random,
+    // One step forward, one step back or staying put, at random, starting
+    // over from the first step every loop.
+    Brownian,
+}
+
+impl Direction {
+    pub fn next(&self) -> Direction {
+        match self {
+            Direction::Forward => Direction::Reverse,
+            Direction::Reverse => Direction::PingPong,
+            Direction::PingPong => Direction::Random,
+            Direction::Random => Direction::Brownian,
+            Direction::Brownian => Direction::Forward,
+        }
+    }
+}
+
+/**
+ * How fast a layer steps through its notes against the clock. A layer at 1/2
+ * takes two steps of the clock for each of its own, and one at 2/1 takes two
+ * of its own for each step of the clock.
+ */
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ClockRate {
+    pub multiplier: usize,
+    pub divider: usize,
+}
+
+impl Default for ClockRate {
+    fn default() -> Self {
+        ClockRate {
+            multiplier: 1,
+            divider: 1,
+        }
+    }
+}
+
+/**
+ * The order an arpeggio goes through the notes of a chord.
+ */
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum ArpeggioMode {
+    #[default]
+    Up,
+    Down,
+    // Up then down again, without playing the ends twice.
+    UpDown,
+    Random,
+    // In the order the rows were added to the step.
+    AsPlayed,
+}
+
+/**
+ * How an arpeggio layer plays the chords on its steps. The rate is against the
+ * clock, not the layer, so a slow layer can still arpeggiate quickly.
+ */
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Arpeggio {
+    pub mode: ArpeggioMode,
+    pub rate: ClockRate,
+    // How many octaves the chord is played across, going up from where it is.
+    pub octaves: usize,
+}
+
+impl Default for Arpeggio {
+    fn default() -> Self {
+        Arpeggio {
+            mode: ArpeggioMode::Up,
+            // Steps mostly last a single step, and four notes to a step gets
+            // through most chords in that time.
+            rate: ClockRate {
+                multiplier: 4,
+                divider: 1,
+            },
+            octaves: 1,
+        }
+    }
 }
 
 /**
  * A Layer represents a collection of notes for an instrument, which can overlap
  * with other layers or be sequenced against other layers.
  */
-#[derive(Clone)]
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
 pub struct Layer {
-    pub notes: [Note; NOTE_COUNT],
+    pub notes: Vec<Note>,
     pub instrument: String,
+    pub kind: LayerKind,
+    // How many steps play before the layer loops. This can be less than the
+    // notes it holds, which are kept around in case it grows back.
+    pub length: usize,
+    pub rate: ClockRate,
+    pub direction: Direction,
+    // Swing of its own, in place of the swing of the project.
+    pub swing: Option<usize>,
+    // The controller shown in the automation view.
+    pub automation_cc: u8,
+    // The chord stamped onto steps, and the rows of the custom one.
+    pub chord: Chord,
+    pub chord_memory: Vec<usize>,
+    // How long a chord takes to strum from its lowest note to its highest, as
+    // a percentage of a step.
+    pub strum: usize,
+    // How the steps are played when the layer is an arpeggio. This is kept
+    // when the layer is something else, in case it goes back.
+    pub arpeggio: Arpeggio,
+    pub mutation: Mutation,
+    // The layer followed when the layer is a follower, kept like the arpeggio.
+    pub follow: Follow,
+}
+
+impl Layer {
+    // How many rows this layer has to pick from.
+    pub fn row_count(&self, settings: &Settings) -> usize {
+        match &self.kind {
+            LayerKind::Melodic | LayerKind::Arpeggio | LayerKind::Follower => {
+                settings.row_count
+            }
+            LayerKind::Drum { voices } => voices.len(),
+        }
+    }
+
+    // The highest the rows can be scrolled before running out of rows.
+    pub fn max_row_offset(&self, settings: &Settings) -> usize {
+        self.row_count(settings)
+            .saturating_sub(settings.visible_rows)
+    }
+
+    // Where the rows sit when the layer is new.
+    pub fn default_row_offset(&self, settings: &Settings) -> usize {
+        match &self.kind {
+            LayerKind::Melodic | LayerKind::Arpeggio | LayerKind::Follower => {
+                DEFAULT_ROW_OFFSET.min(self.max_row_offset(settings))
+            }
+            LayerKind::Drum { .. } => 0,
+        }
+    }
+
+    // The MIDI note a row plays.
+    pub fn pitch(&self, row: usize) -> Option<u8> {
+        match &self.kind {
+            LayerKind::Melodic | LayerKind::Arpeggio | LayerKind::Follower => {
+                u8::try_from(row).ok().filter(|x| *x < 128)
+            }
+            LayerKind::Drum { voices } => voices.get(row).map(|x| x.pitch),
+        }
+    }
+
+    // The MIDI note a row plays once transposed. Drum voices stay where they
+    // are, since moving them would play a different drum.
+    pub fn transposed_pitch(&self, row: usize, transpose: i32) -> Option<u8> {
+        match &self.kind {
+            LayerKind::Melodic | LayerKind::Arpeggio | LayerKind::Follower => {
+                self.pitch(row)
+                    .and_then(|pitch| {
+                        u8::try_from(pitch as i32 + transpose).ok()
+                    })
+                    .filter(|pitch| *pitch < 128)
+            }
+            LayerKind::Drum { .. } => self.pitch(row),
+        }
+    }
+
+    // The row that plays a MIDI note, if there is one.
+    pub fn row(&self, pitch: u8) -> Option<usize> {
+        match &self.kind {
+            LayerKind::Melodic | LayerKind::Arpeggio | LayerKind::Follower => {
+                Some(pitch as usize)
+            }
+            LayerKind::Drum { voices } => {
+                voices.iter().position(|x| x.pitch == pitch)
+            }
+        }
+    }
+}
+
+/**
+ * A pad held down while the grid is played as a keyboard, and the note it
+ * sounds. The note is kept so it can be let go of even if the layer under it
+ * has changed in the meantime.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeldKey {
+    pub x: u32,
+    pub y: u32,
+    pub layer_index: usize,
+    pub pitch: u8,
 }
 
 /**
@@ -32,40 +439,203 @@ pub struct Layer {
 pub struct Player {
     pub active_layer_index: usize,
     pub active_section_index: usize,
+    // Ticks of the clock since the active section started playing from the
+    // top. This only moves along while playing.
     pub interval: usize,
+    // Ticks of the clock since starting up, playing or not. Anything timed on
+    // the controller alone, such as blinking along with a tap, goes by this.
+    pub clock: usize,
+    // The loops of each layer of the active section, counted as it plays.
+    pub loops: Vec<LayerLoops>,
+    // How far each layer has been scrolled, by section and layer index, for
+    // layers that have been. This is where the grid is looking rather than
+    // anything about the layer, so undoing an edit leaves it be.
+    pub row_offsets: BTreeMap<(usize, usize), usize>,
     pub play_mode: PlayMode,
+    // Which page of the active layer is on the grid.
+    pub page: usize,
+    // Turn the page along with the playhead.
+    pub follow: bool,
+    // Steps only played during a fill are played.
+    pub fill: bool,
+    pub record: RecordMode,
+    // The step of the active layer a key lands on in step entry.
+    pub cursor: usize,
+    // Keys on the keyboard transpose rather than record.
+    pub transpose_input: bool,
+    // How many semitones melodic layers are played away from how they are
+    // written.
+    pub transpose: i32,
+    pub view: View,
+    // Pads held down in the keyboard view.
+    pub keys: Vec<HeldKey>,
+    // The rhythm the Euclid view fills rows with, and the row of the active
+    // layer it is filling, once one has been picked.
+    pub euclid: Euclid,
+    pub euclid_row: Option<usize>,
+    pub morph: Morph,
+    // Effects held down over playback, in the order they were pressed.
+    pub effects: Vec<HeldEffect>,
+    pub tempo: Tempo,
+    // What was last copied. Shared rather than cloned along with the rest of
+    // the player, since a section is no small thing to copy on every tick.
+    pub clipboard: Option<Arc<Clipboard>>,
+}
+
+impl Player {
+    // The lowest row of a layer showing on the grid. Layers have far more rows
+    // than any grid, so the grid is a window that can be scrolled up and down.
+    pub fn row_offset(
+        &self,
+        settings: &Settings,
+        section_index: usize,
+        layer_index: usize,
+        layer: &Layer,
+    ) -> usize {
+        match self.row_offsets.get(&(section_index, layer_index)) {
+            Some(row_offset) => {
+                (*row_offset).min(layer.max_row_offset(settings))
+            }
+            None => layer.default_row_offset(settings),
+        }
+    }
+
+    pub fn layer_loops(&self, layer_index: usize) -> LayerLoops {
+        self.loops.get(layer_index).copied().unwrap_or_default()
+    }
+}
+
+/**
+ * Something copied, waiting to be pasted onto something of the same kind.
+ */
+#[derive(Clone, PartialEq)]
+pub enum Clipboard {
+    Step(Note),
+    Layer(Layer),
+    Section(Section),
 }
 
 /**
  * Sections contain one or more layers. All of the layers in a section are
  * played in parallel. Sections can be sequenced together.
  */
-#[derive(Clone)]
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
 pub struct Section {
     pub layers: Vec<Layer>,
 }
 
+/**
+ * Settings shape the project as a whole. Every section and layer is built to
+ * these dimensions. Devices show as much of them as they can.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    // How many steps each layer has.
+    pub section_length: usize,
+    // How many steps are shown at once. This is normally the width of the
+    // device's grid.
+    pub page_length: usize,
+    // How many rows each layer has to pick from. Out of the box this is one
+    // row for every MIDI note.
+    pub row_count: usize,
+    // How many rows are shown at once. This is normally the height of the
+    // device's grid.
+    pub visible_rows: usize,
+    pub layer_count: usize,
+    pub section_count: usize,
+    // Seeds anything left to chance, so a performance can be played back.
+    // A project keeps the seed it was made with.
+    pub seed: u64,
+    // How far into each pair of steps the second one lands, as a percentage.
+    // 50 is straight, and 66 is close to triplets.
+    pub swing: usize,
+    pub groove: Option<Groove>,
+    pub scale: Scale,
+    // The voices the rows of a layer are bound to when it becomes a drum
+    // layer.
+    pub drum_voices: Vec<DrumVoice>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            section_length: 8,
+            page_length: 8,
+            row_count: 128,
+            visible_rows: 8,
+            layer_count: 8,
+            section_count: 8,
+            seed: 0,
+            swing: 50,
+            groove: None,
+            scale: Scale::default(),
+            drum_voices: general_midi_drum_voices(),
+        }
+    }
+}
+
+impl Settings {
+    // The longest a layer can be made. Layers start out as long as a section,
+    // and can be made longer, up to as many pages as there are track buttons
+    // to pick them from.
+    pub fn max_layer_length(&self) -> usize {
+        self.section_length.max(MAX_LAYER_PAGES * self.page_length)
+    }
+
+    // Pages any layer could run to.
+    pub fn page_count(&self) -> usize {
+        self.max_layer_length().div_ceil(self.page_length)
+    }
+
+    // The controller value set by the row of the automation view, from the
+    // bottom. The top row is all the way up.
+    pub fn lock_value(&self, row: usize) -> u8 {
+        ((row + 1) * 127 / self.visible_rows).min(127) as u8
+    }
+
+    // How many rows of the automation view a controller value lights.
+    pub fn lock_height(&self, value: u8) -> usize {
+        (value as usize * self.visible_rows).div_ceil(127)
+    }
+}
+
+const MAX_LAYER_PAGES: usize = 8;
+// Start new layers around middle C.
+const DEFAULT_ROW_OFFSET: usize = 60;
+// Playing this key on the keyboard puts the transposition back to nothing.
+pub const TRANSPOSE_REFERENCE: u8 = 60;
+// Filter cutoff, on most instruments that follow the General MIDI names.
+const DEFAULT_AUTOMATION_CC: u8 = 74;
+
 #[derive(Default, Clone)]
 pub struct GlobalState {
     pub sections: Vec<Section>,
     pub player: Player,
+    pub settings: Settings,
 }
 
-pub fn initial_state() -> GlobalState {
+pub fn initial_state(settings: Settings) -> GlobalState {
     GlobalState {
-        sections: (0..8)
+        sections: (0..settings.section_count)
             .map(|_| Section {
-                layers: (0..8)
+                layers: (0..settings.layer_count)
                     .map(|_| Layer {
                         instrument: "Beep Boops".to_string(),
-                        notes: (0..8)
-                            .map(|_| Note {
-                                octaves: vec![],
-                                length: 0,
-                            })
-                            .collect::<Vec<Note>>()
-                            .try_into()
-                            .unwrap(),
+                        kind: LayerKind::Melodic,
+                        length: settings.section_length,
+                        rate: ClockRate::default(),
+                        direction: Direction::Forward,
+                        swing: None,
+                        automation_cc: DEFAULT_AUTOMATION_CC,
+                        chord: Chord::default(),
+                        chord_memory: vec![],
+                        strum: 0,
+                        arpeggio: Arpeggio::default(),
+                        mutation: Mutation::default(),
+                        follow: Follow::default(),
+                        notes: (0..settings.section_length)
+                            .map(|_| Note::default())
+                            .collect::<Vec<Note>>(),
                     })
                     .collect::<Vec<Layer>>(),
             })
@@ -74,7 +644,26 @@ pub fn initial_state() -> GlobalState {
             active_layer_index: 0,
             active_section_index: 0,
             interval: 0,
+            clock: 0,
+            loops: vec![],
+            row_offsets: BTreeMap::new(),
             play_mode: PlayMode::Paused,
+            page: 0,
+            follow: true,
+            fill: false,
+            record: RecordMode::Off,
+            cursor: 0,
+            transpose_input: false,
+            transpose: 0,
+            view: View::Steps,
+            keys: vec![],
+            euclid: Euclid::default(),
+            euclid_row: None,
+            morph: Morph::default(),
+            effects: vec![],
+            tempo: Tempo::default(),
+            clipboard: None,
         },
+        settings,
     }
 }
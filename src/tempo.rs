@@ -0,0 +1,127 @@
+use crate::playback::TICKS_PER_STEP;
+use std::time::Duration;
+
+// A step a second, which is how fast the clock ran before it could be tapped.
+// Each tap is a step.
+pub const DEFAULT_BPM: f64 = 60.0;
+const MIN_BPM: f64 = 20.0;
+const MAX_BPM: f64 = 300.0;
+// How many of the gaps between the latest taps are averaged.
+const TAP_GAPS: usize = 6;
+// A tap this long after the last one starts counting over. Any longer and the
+// slowest tempo couldn't be tapped.
+const TAP_TIMEOUT_MS: u64 = (60_000.0 / MIN_BPM) as u64;
+// Gaps further than this from the middle gap, as a percentage of it, are taken
+// to be a tap missed or fumbled, and left out of the average.
+const TAP_OUTLIER_PERCENT: u64 = 25;
+// How many beats the play button blinks for after a tap.
+const TAP_BLINK_BEATS: usize = 8;
+
+/**
+ * How fast the clock runs, and the taps it was last set from. Taps are times
+ * in milliseconds after starting up.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tempo {
+    pub bpm: f64,
+    pub taps: Vec<u64>,
+    // The tick of the clock on the last tap.
+    pub tapped: Option<usize>,
+}
+
+impl Default for Tempo {
+    fn default() -> Self {
+        Tempo {
+            bpm: DEFAULT_BPM,
+            taps: vec![],
+            tapped: None,
+        }
+    }
+}
+
+impl Tempo {
+    // The tempo after another tap. A single tap only starts the count.
+    pub fn tap(&self, ms: u64, clock: usize) -> Tempo {
+        let mut taps = match self.taps.last() {
+            Some(last) if ms >= *last && ms - last <= TAP_TIMEOUT_MS => {
+                self.taps.clone()
+            }
+            _ => vec![],
+        };
+        taps.push(ms);
+        if taps.len() > TAP_GAPS + 1 {
+            taps.remove(0);
+        }
+        Tempo {
+            bpm: tapped_bpm(&taps).unwrap_or(self.bpm),
+            taps,
+            tapped: Some(clock),
+        }
+    }
+
+    pub fn tick_duration(&self) -> Duration {
+        Duration::from_secs_f64(60.0 / (self.bpm * TICKS_PER_STEP as f64))
+    }
+
+    // For a little while after a tap the play button blinks along with the
+    // beat, lit for the first half of each. Beats are counted from the tap,
+    // so the blink lands on it. None once it has stopped.
+    pub fn blink(&self, clock: usize) -> Option<bool> {
+        self.tapped
+            .map(|tapped| clock.saturating_sub(tapped))
+            .filter(|since| *since < TAP_BLINK_BEATS * TICKS_PER_STEP)
+            .map(|since| since % TICKS_PER_STEP < TICKS_PER_STEP / 2)
+    }
+}
+
+fn tapped_bpm(taps: &[u64]) -> Option<f64> {
+    let gaps = taps
+        .windows(2)
+        .map(|pair| pair[1] - pair[0])
+        .collect::<Vec<u64>>();
+    let mut sorted = gaps.clone();
+    sorted.sort();
+    let middle = *sorted.get(sorted.len() / 2)?;
+    let kept = gaps
+        .iter()
+        .filter(|gap| {
+            gap.abs_diff(middle) * 100 <= middle * TAP_OUTLIER_PERCENT
+        })
+        .collect::<Vec<&u64>>();
+    let total = kept.iter().copied().sum::<u64>();
+    (total > 0).then(|| {
+        (60_000.0 * kept.len() as f64 / total as f64).clamp(MIN_BPM, MAX_BPM)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn taps_set_the_tempo_from_the_gaps_between_them() {
+        assert_eq!(tapped_bpm(&[0, 500, 1000, 1500]), Some(120.0));
+        assert_eq!(tapped_bpm(&[0]), None);
+    }
+
+    #[test]
+    fn fumbled_taps_are_left_out() {
+        assert_eq!(tapped_bpm(&[0, 500, 1000, 1100, 1600]), Some(120.0));
+    }
+
+    #[test]
+    fn the_slowest_tempo_can_be_tapped() {
+        let slowest = (60_000.0 / MIN_BPM) as u64;
+        let tempo = Tempo::default().tap(0, 0).tap(slowest, 0);
+        assert_eq!(tempo.bpm, MIN_BPM);
+    }
+
+    #[test]
+    fn the_blink_starts_on_the_tap() {
+        let tempo = Tempo::default().tap(0, TICKS_PER_STEP / 2);
+        assert_eq!(tempo.blink(TICKS_PER_STEP / 2), Some(true));
+        assert_eq!(tempo.blink(TICKS_PER_STEP), Some(false));
+        assert_eq!(tempo.blink(TICKS_PER_STEP * 3 / 2), Some(true));
+        assert_eq!(Tempo::default().blink(0), None);
+    }
+}